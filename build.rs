@@ -0,0 +1,36 @@
+//! Resolves the folder the `embed` feature compiles into the binary, the one
+//! in `TRSO_EMBED_DIR` or else `migrations` next to Cargo.toml.
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=TRSO_EMBED_DIR");
+    if env::var_os("CARGO_FEATURE_EMBED").is_none() {
+        return;
+    }
+
+    let manifest = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    // also picks up a migrations folder created after the first build
+    println!("cargo:rerun-if-changed={}", manifest.join("migrations").display());
+    let dir = match env::var("TRSO_EMBED_DIR") {
+        Ok(dir) => {
+            let dir = manifest.join(dir);
+            if !dir.is_dir() {
+                panic!("TRSO_EMBED_DIR {} is not a folder", dir.display());
+            }
+            dir
+        }
+        // without a migrations folder the binary embeds none, e.g. when the
+        // crate is checked with --all-features
+        Err(_) if !manifest.join("migrations").is_dir() => {
+            println!("cargo:warning=no migrations folder next to Cargo.toml, embedding no migrations");
+            let empty = PathBuf::from(env::var("OUT_DIR").unwrap()).join("no-migrations");
+            fs::create_dir_all(&empty).unwrap();
+            empty
+        }
+        Err(_) => manifest.join("migrations"),
+    };
+
+    println!("cargo:rerun-if-changed={}", dir.display());
+    println!("cargo:rustc-env=TRSO_EMBEDDED_MIGRATIONS={}", dir.display());
+}
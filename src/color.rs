@@ -0,0 +1,39 @@
+//! ANSI colors of the terminal output, only used with `Config::color`.
+
+use std::{
+    fmt::Display,
+    io::{stderr, stdout, IsTerminal},
+};
+
+use crate::Config;
+
+/// Colors of the output, green for applied, yellow for skipped and red for errors.
+#[derive(Clone, Copy, Debug)]
+pub enum Color {
+    Red = 31,
+    Green = 32,
+    Yellow = 33,
+}
+
+/// A log message in `color` when stderr, where the log goes, is a terminal.
+pub(crate) fn log(config: &Config, color: Color, text: impl Display) -> String {
+    if config.color && stderr().is_terminal() {
+        paint(color, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Printed output in `color` when stdout is a terminal.
+pub(crate) fn out(config: &Config, color: Color, text: impl Display) -> String {
+    if config.color && stdout().is_terminal() {
+        paint(color, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// `text` wrapped in the escape codes of `color`, the text itself is unchanged.
+pub fn paint(color: Color, text: impl Display) -> String {
+    format!("\x1b[{}m{}\x1b[0m", color as u8, text)
+}
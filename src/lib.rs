@@ -0,0 +1,3996 @@
+//! Applies SQL migration files from a folder to a local or remote Turso database.
+//!
+//! The migrator can be embedded into an application, for example to migrate the
+//! database on startup:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), trso_migrator::AppError> {
+//! let db = libsql::Builder::new_local("local.db").build().await.unwrap();
+//! let conn = db.connect().unwrap();
+//!
+//! let report = trso_migrator::migrate(&conn, "migrations").await?;
+//! println!("applied {}", report.applied.join(", "));
+//! # Ok(())
+//! # }
+//! ```
+
+mod color;
+mod preview;
+mod source;
+mod split;
+mod template;
+mod vars;
+mod webhook;
+
+#[cfg(feature = "embed")]
+pub use source::EmbeddedSource;
+pub use color::{paint, Color};
+pub use source::{ArchiveSource, FsSource, MigrationEntry, MigrationSource};
+pub use webhook::{notify_webhook, RunSummary};
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use libsql::{Builder, Connection, Database, TransactionBehavior};
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Settings of a migration run. Its `Debug` output masks the token.
+pub struct Config {
+    pub url_or_path: String,
+
+    pub local: bool,
+
+    pub token: String,
+
+    /// Folder of the migration files, several folders are separated like in
+    /// `PATH` and their files are applied in one combined order.
+    pub migrations_path: String,
+
+    pub direction: Direction,
+
+    pub dry_run: bool,
+
+    /// With `dry_run`, write the pending migrations and the rows recording them
+    /// to this file as one transaction instead of printing them.
+    pub output_sql: Option<PathBuf>,
+
+    pub skip_checksum_verify: bool,
+
+    /// Match pending files to applied migrations by checksum too, a renamed
+    /// file whose content was applied under its old name isn't run again.
+    pub match_checksums: bool,
+
+    pub single_transaction: bool,
+
+    /// In single transaction mode, commit and start a new transaction after
+    /// this many applied files, `0` keeps everything in one transaction.
+    pub commit_every: usize,
+
+    /// Run each file with a single `execute_batch` instead of statement by statement.
+    pub batch_execution: bool,
+
+    /// Fail instead of warning when a pending file sorts before the latest applied one.
+    pub strict_order: bool,
+
+    /// How long to wait for a concurrent run to release the migration lock,
+    /// zero fails right away.
+    pub lock_timeout_secs: u64,
+
+    /// Local file of an embedded replica of the remote database, migrations
+    /// run against it and are synced to the primary.
+    pub replica_path: Option<String>,
+
+    /// Seconds a sync of the embedded replica may take, zero waits forever.
+    pub sync_timeout_secs: u64,
+
+    /// Only warn when the sync before a run fails, the run then starts from
+    /// the last synced state. The sync after a run always has to succeed.
+    pub sync_best_effort: bool,
+
+    /// Table the applied migrations are recorded in, `migrations` by default.
+    pub table_name: String,
+
+    /// Column of `table_name` holding the migration file names, set to adopt
+    /// the table of another tool.
+    pub file_name_column: String,
+
+    /// Column of `table_name` holding when a migration was applied.
+    pub applied_at_column: String,
+
+    /// Column of `table_name` holding the leading number of a migration file,
+    /// only written when set.
+    pub version_column: Option<String>,
+
+    /// `Tracking::UserVersion` replaces the migrations table and the
+    /// migration lock with `PRAGMA user_version`.
+    pub tracking: Tracking,
+
+    /// Collect `.sql` files from subfolders too, recorded by their relative path.
+    pub recursive: bool,
+
+    pub output: OutputFormat,
+
+    /// How many times connecting is retried before giving up, with an
+    /// exponential backoff starting at 500ms.
+    pub connect_retries: u32,
+
+    /// How many times a migration failing with a transient network error is
+    /// rolled back and run again, with an exponential backoff starting at 500ms.
+    /// Zero never retries, files in a single transaction are never retried.
+    pub exec_retries: u32,
+
+    /// Seconds a single connection attempt or migration file may take before
+    /// it is aborted and rolled back, zero waits forever.
+    pub statement_timeout_secs: u64,
+
+    /// Whether an embedded replica sees its own writes before they are synced back.
+    pub read_your_writes: bool,
+
+    /// Namespace sent to the primary of an embedded replica.
+    pub namespace: Option<String>,
+
+    /// Regex every migration file name has to match before a run, unchecked when unset.
+    pub naming_pattern: Option<String>,
+
+    /// Stop after applying this migration file, later ones stay pending.
+    pub target: Option<String>,
+
+    /// Apply only this migration file, every other one stays pending.
+    pub only: Option<String>,
+
+    /// Ignore this migration file and every pending one sorting before it.
+    pub since: Option<String>,
+
+    /// Apply at most this many pending migrations, the rest stays pending.
+    pub count: Option<usize>,
+
+    /// Apply only the pending migrations with this `-- trso:tags` tag.
+    pub tag: Option<String>,
+
+    /// Url a JSON summary of every run is posted to, masked in `Debug` since
+    /// webhook urls usually embed a secret.
+    pub webhook_url: Option<String>,
+
+    /// Replace `${VAR}` placeholders in the migrations with environment variables.
+    pub template: bool,
+
+    /// Fail instead of warning when an applied migration's file is missing.
+    pub strict: bool,
+
+    /// SQL file run before every migration run without being recorded,
+    /// `_before.sql` in `migrations_path` by default. Skipped when it doesn't exist.
+    pub before_hook: Option<String>,
+
+    /// SQL file run after every successful migration run without being
+    /// recorded, `_after.sql` in `migrations_path` by default.
+    pub after_hook: Option<String>,
+
+    /// PRAGMAs set on every new connection as name and value, e.g.
+    /// `("busy_timeout", "5000")`.
+    pub pragmas: Vec<(String, String)>,
+
+    /// Allow several migrations with the same leading number, which are then
+    /// ordered by the rest of their names.
+    pub allow_duplicate_versions: bool,
+
+    /// Environment of the run, e.g. `staging`. Migrations whose first line is
+    /// `-- trso:env <name>` only run in that environment.
+    pub environment: Option<String>,
+
+    /// Set from another task, e.g. on Ctrl-C, to stop the run before the next
+    /// migration file. The file that is running is never interrupted.
+    pub cancel: Arc<AtomicBool>,
+
+    /// Color the log messages and the status table when they go to a terminal.
+    pub color: bool,
+
+    /// Milliseconds between two looks at the migration files in `--watch`.
+    pub watch_poll_ms: u64,
+
+    /// Milliseconds the migration files have to stay unchanged in `--watch`
+    /// before a burst of saves is applied.
+    pub watch_debounce_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            url_or_path: String::new(),
+            local: false,
+            token: String::new(),
+            migrations_path: String::new(),
+            direction: Direction::default(),
+            dry_run: false,
+            output_sql: None,
+            skip_checksum_verify: false,
+            match_checksums: false,
+            single_transaction: false,
+            commit_every: 0,
+            batch_execution: false,
+            strict_order: false,
+            lock_timeout_secs: 0,
+            replica_path: None,
+            sync_timeout_secs: 60,
+            sync_best_effort: false,
+            table_name: String::from("migrations"),
+            file_name_column: String::from("file_name"),
+            applied_at_column: String::from("applied_at"),
+            version_column: None,
+            tracking: Tracking::default(),
+            recursive: false,
+            output: OutputFormat::default(),
+            connect_retries: 5,
+            exec_retries: 0,
+            statement_timeout_secs: 0,
+            read_your_writes: true,
+            namespace: None,
+            naming_pattern: None,
+            target: None,
+            only: None,
+            since: None,
+            template: false,
+            strict: false,
+            before_hook: None,
+            after_hook: None,
+            pragmas: Vec::new(),
+            allow_duplicate_versions: false,
+            environment: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+            color: false,
+            watch_poll_ms: 500,
+            watch_debounce_ms: 300,
+            count: None,
+            tag: None,
+            webhook_url: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config")
+            .field("url_or_path", &self.url_or_path)
+            .field("local", &self.local)
+            .field("token", &redact(&self.token))
+            .field("migrations_path", &self.migrations_path)
+            .field("direction", &self.direction)
+            .field("dry_run", &self.dry_run)
+            .field("output_sql", &self.output_sql)
+            .field("skip_checksum_verify", &self.skip_checksum_verify)
+            .field("match_checksums", &self.match_checksums)
+            .field("single_transaction", &self.single_transaction)
+            .field("commit_every", &self.commit_every)
+            .field("batch_execution", &self.batch_execution)
+            .field("strict_order", &self.strict_order)
+            .field("lock_timeout_secs", &self.lock_timeout_secs)
+            .field("replica_path", &self.replica_path)
+            .field("sync_timeout_secs", &self.sync_timeout_secs)
+            .field("sync_best_effort", &self.sync_best_effort)
+            .field("table_name", &self.table_name)
+            .field("file_name_column", &self.file_name_column)
+            .field("applied_at_column", &self.applied_at_column)
+            .field("version_column", &self.version_column)
+            .field("tracking", &self.tracking)
+            .field("recursive", &self.recursive)
+            .field("output", &self.output)
+            .field("connect_retries", &self.connect_retries)
+            .field("exec_retries", &self.exec_retries)
+            .field("statement_timeout_secs", &self.statement_timeout_secs)
+            .field("read_your_writes", &self.read_your_writes)
+            .field("namespace", &self.namespace)
+            .field("naming_pattern", &self.naming_pattern)
+            .field("target", &self.target)
+            .field("only", &self.only)
+            .field("since", &self.since)
+            .field("template", &self.template)
+            .field("strict", &self.strict)
+            .field("before_hook", &self.before_hook)
+            .field("after_hook", &self.after_hook)
+            .field("pragmas", &self.pragmas)
+            .field("allow_duplicate_versions", &self.allow_duplicate_versions)
+            .field("environment", &self.environment)
+            .field("cancel", &self.cancel)
+            .field("color", &self.color)
+            .field("watch_poll_ms", &self.watch_poll_ms)
+            .field("watch_debounce_ms", &self.watch_debounce_ms)
+            .field("count", &self.count)
+            .field("tag", &self.tag)
+            .field("webhook_url", &self.webhook_url.as_deref().map(redact))
+            .finish()
+    }
+}
+
+/// Stands in for a secret in `Debug` output, empty values stay visible.
+fn redact(secret: &str) -> &'static str {
+    if secret.is_empty() {
+        ""
+    } else {
+        "***"
+    }
+}
+
+#[derive(Debug, Default)]
+pub enum Direction {
+    #[default]
+    Up,
+    Down,
+}
+
+/// How the outcome of a run is printed to stdout.
+#[derive(Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    /// Human readable log lines only.
+    #[default]
+    Text,
+    /// A JSON array with a [`FileResult`] per migration file at the end of the run.
+    Json,
+}
+
+/// Where applied migrations are tracked.
+#[derive(Debug, Default, PartialEq)]
+pub enum Tracking {
+    /// A row per migration in `Config::table_name`, with its checksum.
+    #[default]
+    Table,
+    /// Only the number of the last applied migration in `PRAGMA user_version`.
+    UserVersion,
+}
+
+/// Which migrations [`check_down_files`] expects a `.down.sql` file for.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DownPairing {
+    /// Only `.up.sql` files, a plain `.sql` file can't be rolled back on purpose.
+    #[default]
+    UpFiles,
+    /// Every migration, `.up.sql` and plain `.sql` files alike.
+    All,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Applied,
+    Skipped,
+    /// Not applied because of a dry run.
+    Pending,
+    Failed,
+    /// Applied in single transaction mode, then undone by a later failure.
+    RolledBack,
+    /// Not applied because it is tagged for another environment.
+    SkippedEnv,
+    /// A `-- trso:optional` migration that failed, it was undone and stays pending.
+    FailedOptional,
+}
+
+/// Outcome of a single migration file, printed in JSON output mode.
+#[derive(Debug, Serialize)]
+pub struct FileResult {
+    pub name: String,
+
+    pub status: FileStatus,
+
+    pub duration_ms: Option<u128>,
+
+    pub error: Option<String>,
+}
+
+/// Errors returned by the migrator.
+#[derive(Debug)]
+pub enum AppError {
+    DatabaseError(String),
+    IOError(String),
+    ConfigError(String),
+    /// Applying or rolling back `file` failed, `source` is why. Nothing of the
+    /// file is recorded.
+    MigrationFailed { file: String, source: Box<AppError> },
+    /// Another run held the migration lock for longer than this.
+    LockTimeout(Duration),
+    /// The run was stopped through `Config::cancel`.
+    Interrupted(String),
+    /// Syncing the embedded replica with its primary failed or timed out, the
+    /// migrations themselves may have succeeded.
+    SyncFailed(String),
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::DatabaseError(msg) => write!(f, "database error: {}", msg),
+            AppError::IOError(msg) => write!(f, "io error: {}", msg),
+            AppError::ConfigError(msg) => write!(f, "config error: {}", msg),
+            AppError::MigrationFailed { file, source } => {
+                write!(f, "migration {} failed: {}", file, source)
+            }
+            AppError::LockTimeout(waited) => {
+                write!(f, "migration lock is held by another run")?;
+                if !waited.is_zero() {
+                    write!(f, " after waiting {:?}", waited)?;
+                }
+                write!(f, ", if no other run is active delete the row from migration_lock")
+            }
+            AppError::Interrupted(msg) => write!(f, "interrupted: {}", msg),
+            AppError::SyncFailed(msg) => write!(f, "replica sync failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for AppError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AppError::MigrationFailed { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl AppError {
+    /// What went wrong in one word, for a failed migration that of its cause.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::DatabaseError(_) => "database",
+            AppError::IOError(_) => "io",
+            AppError::ConfigError(_) => "config",
+            AppError::MigrationFailed { source, .. } => source.kind(),
+            AppError::LockTimeout(_) => "lock_timeout",
+            AppError::Interrupted(_) => "interrupted",
+            AppError::SyncFailed(_) => "sync",
+        }
+    }
+}
+
+/// A failed run in `TRSO_OUTPUT=json` mode, printed to stderr so it doesn't
+/// mix with the results of the files on stdout.
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    /// Always `error`.
+    pub status: &'static str,
+
+    /// `connection`, `database`, `io`, `config`, `lock_timeout`, `interrupted` or `sync`.
+    pub kind: &'static str,
+
+    /// The migration file that failed, if the run stopped at one.
+    pub file: Option<String>,
+
+    pub message: String,
+}
+
+impl ErrorReport {
+    pub fn new(e: &AppError) -> Self {
+        let file = match e {
+            AppError::MigrationFailed { file, .. } => Some(file.clone()),
+            _ => None,
+        };
+
+        ErrorReport {
+            status: "error",
+            kind: e.kind(),
+            file,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Outcome of a migration run, every list is in the order the files were visited.
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    /// Names of the applied migrations, in dry run mode of the pending ones.
+    pub applied: Vec<String>,
+
+    /// Names of the migrations that were already applied before the run.
+    pub skipped: Vec<String>,
+
+    /// Names of the pending migrations tagged for another environment.
+    pub skipped_env: Vec<String>,
+
+    /// Names of the `-- trso:optional` migrations that failed and were undone.
+    pub failed_optional: Vec<String>,
+}
+
+/// Connection settings parsed from a DSN such as
+/// `libsql://db.turso.io?authToken=...` or `file:local.db`. Its `Debug`
+/// output masks the token.
+pub struct Dsn {
+    /// Url of the remote database or path of the local file.
+    pub url_or_path: String,
+
+    /// The `authToken` query parameter, never required for local DSNs.
+    pub token: Option<String>,
+
+    /// `file:` DSNs and plain paths are opened with `Builder::new_local`.
+    pub local: bool,
+
+    /// Every other query parameter in the order it appears. `tls` and `secure`
+    /// are already applied to the url, `readYourWrites` and `namespace` are
+    /// forwarded to embedded replicas.
+    pub params: Vec<(String, String)>,
+}
+
+/// Parses a `libsql://`, `http://`, `https://` or `file:` DSN, or a plain path
+/// of a local file. Remote schemes require the `authToken` query parameter.
+pub fn parse_dsn(dsn: &str) -> Result<Dsn, AppError> {
+    let (base, query) = match dsn.split_once('?') {
+        Some((base, query)) => (base, Some(query)),
+        None => (dsn, None),
+    };
+
+    let mut token = None;
+    let mut params = Vec::new();
+    for pair in query.unwrap_or("").split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if key == "authToken" {
+            token = Some(value.to_string());
+        } else {
+            params.push((key.to_string(), value.to_string()));
+        }
+    }
+
+    if let Some(path) = base.strip_prefix("file:") {
+        // both file:local.db and file:///abs/local.db are accepted
+        let path = path.strip_prefix("//").unwrap_or(path);
+        if path.is_empty() {
+            return Err(AppError::ConfigError(String::from(
+                "file: DSN must include the database path",
+            )));
+        }
+
+        return Ok(Dsn {
+            url_or_path: path.to_string(),
+            token,
+            local: true,
+            params,
+        });
+    }
+
+    let scheme = match base.split_once("://") {
+        Some((scheme, _)) => scheme,
+        // a plain path like local.db is a local file as well
+        None if !base.is_empty() => {
+            return Ok(Dsn {
+                url_or_path: base.to_string(),
+                token,
+                local: true,
+                params,
+            })
+        }
+        None => {
+            return Err(AppError::ConfigError(String::from(
+                "DSN must start with libsql://, http://, https:// or file:, or be a path",
+            )))
+        }
+    };
+
+    if !matches!(scheme, "libsql" | "http" | "https") {
+        return Err(AppError::ConfigError(format!(
+            "unsupported DSN scheme {}://, expected libsql://, http://, https:// or file:",
+            scheme
+        )));
+    }
+
+    let mut url = base.to_string();
+    for (key, value) in &params {
+        if key != "tls" && key != "secure" {
+            continue;
+        }
+
+        // libsql:// is served over https, tls=false talks plain http instead
+        let secure = parse_dsn_flag(key, value)?;
+        let host = &base[scheme.len() + "://".len()..];
+        url = format!("{}://{}", if secure { "https" } else { "http" }, host);
+    }
+
+    for (key, value) in &params {
+        if key == "readYourWrites" {
+            parse_dsn_flag(key, value)?;
+        }
+    }
+
+    match token {
+        Some(token) if !token.is_empty() => Ok(Dsn {
+            url_or_path: url,
+            token: Some(token),
+            local: false,
+            params,
+        }),
+        _ => Err(AppError::ConfigError(String::from(
+            "remote DSN must include authToken query parameter",
+        ))),
+    }
+}
+
+impl std::fmt::Debug for Dsn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dsn")
+            .field("url_or_path", &self.url_or_path)
+            .field("token", &self.token.as_deref().map(redact))
+            .field("local", &self.local)
+            .field("params", &self.params)
+            .finish()
+    }
+}
+
+impl Dsn {
+    /// Value of the last occurrence of a query parameter.
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The `readYourWrites` parameter, `true` when it isn't set.
+    pub fn read_your_writes(&self) -> bool {
+        self.param("readYourWrites")
+            .is_none_or(|value| value == "true" || value == "1")
+    }
+}
+
+fn parse_dsn_flag(key: &str, value: &str) -> Result<bool, AppError> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(AppError::ConfigError(format!(
+            "DSN parameter {} should be either true or false",
+            key
+        ))),
+    }
+}
+
+pub async fn get_database(config: &Config) -> Result<Database, libsql::Error> {
+    let db = if config.local {
+        Builder::new_local(&config.url_or_path).build().await?
+    } else if let Some(replica_path) = &config.replica_path {
+        let mut builder = Builder::new_remote_replica(
+            replica_path,
+            config.url_or_path.clone(),
+            config.token.clone(),
+        )
+        .read_your_writes(config.read_your_writes);
+        if let Some(namespace) = &config.namespace {
+            builder = builder.namespace(namespace);
+        }
+        builder.build().await?
+    } else {
+        Builder::new_remote(config.url_or_path.clone(), config.token.clone())
+            .build()
+            .await?
+    };
+
+    Ok(db)
+}
+
+/// Opens the database and a connection to it, a failed attempt is retried up to
+/// `connect_retries` times so the migrator can start before the database is
+/// reachable. The last error is returned once the retries are exhausted.
+pub async fn connect(config: &Config) -> Result<(Database, Connection), libsql::Error> {
+    let mut backoff = Duration::from_millis(500);
+    let mut attempt = 0;
+    loop {
+        match try_connect(config).await {
+            Ok(connected) => return Ok(connected),
+            Err(e) if attempt < config.connect_retries => {
+                attempt += 1;
+                warn!(
+                    "Cannot connect to the database ({}), retrying in {:?} ({}/{})",
+                    e, backoff, attempt, config.connect_retries
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn try_connect(config: &Config) -> Result<(Database, Connection), libsql::Error> {
+    if config.statement_timeout_secs == 0 {
+        return open_connection(config).await;
+    }
+
+    let timeout = Duration::from_secs(config.statement_timeout_secs);
+    match tokio::time::timeout(timeout, open_connection(config)).await {
+        Ok(result) => result,
+        Err(_) => Err(libsql::Error::ConnectionFailed(format!(
+            "timed out after {:?}",
+            timeout
+        ))),
+    }
+}
+
+async fn open_connection(config: &Config) -> Result<(Database, Connection), libsql::Error> {
+    let db = get_database(config).await?;
+    let conn = db.connect()?;
+    // remote builders don't touch the network, a query tells whether the
+    // database is actually reachable
+    conn.query("SELECT 1", ()).await?;
+
+    let plain = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    for (name, value) in &config.pragmas {
+        if !plain(name) || !plain(value) {
+            return Err(libsql::Error::Misuse(format!(
+                "invalid PRAGMA {} = {}",
+                name, value
+            )));
+        }
+
+        // journal_mode answers with the new mode, query reads past that row
+        conn.query(&format!("PRAGMA {} = {}", name, value), ()).await?;
+    }
+
+    // SQLite waits for a lock itself as long as the lock timeout allows
+    let busy_timeout_set = config.pragmas.iter().any(|(name, _)| name == "busy_timeout");
+    if config.local && config.lock_timeout_secs > 0 && !busy_timeout_set {
+        let sql = format!("PRAGMA busy_timeout = {}", config.lock_timeout_secs * 1000);
+        conn.query(&sql, ()).await?;
+    }
+
+    Ok((db, conn))
+}
+
+pub async fn get_connection(config: &Config) -> Result<Connection, libsql::Error> {
+    let (_, conn) = connect(config).await?;
+
+    Ok(conn)
+}
+
+/// Syncs an embedded replica with its primary, giving up after
+/// `Config::sync_timeout_secs`.
+pub async fn sync_replica(db: &Database, config: &Config) -> Result<(), AppError> {
+    if config.sync_timeout_secs == 0 {
+        return match db.sync().await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(AppError::SyncFailed(e.to_string())),
+        };
+    }
+
+    let timeout = Duration::from_secs(config.sync_timeout_secs);
+    match tokio::time::timeout(timeout, db.sync()).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(AppError::SyncFailed(e.to_string())),
+        Err(_) => Err(AppError::SyncFailed(format!(
+            "timed out after {:?}, raise TRSO_SYNC_TIMEOUT_SECS for a slow network",
+            timeout
+        ))),
+    }
+}
+
+/// The table name is interpolated into SQL, so only plain identifiers are allowed.
+pub fn validate_table_name(table: &str) -> Result<(), AppError> {
+    let mut chars = table.chars();
+    let valid = match chars.next() {
+        Some(first) => {
+            (first.is_ascii_alphabetic() || first == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    };
+
+    if !valid {
+        return Err(AppError::ConfigError(format!(
+            "invalid table name {:?}, it must match ^[A-Za-z_][A-Za-z0-9_]*$",
+            table
+        )));
+    }
+
+    Ok(())
+}
+
+/// The mapped column names are interpolated into SQL like the table name.
+fn validate_columns(config: &Config) -> Result<(), AppError> {
+    let columns = [&config.file_name_column, &config.applied_at_column];
+    for column in columns.into_iter().chain(&config.version_column) {
+        if validate_table_name(column).is_err() {
+            return Err(AppError::ConfigError(format!(
+                "invalid column name {:?}, it must match ^[A-Za-z_][A-Za-z0-9_]*$",
+                column
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the migrations table belongs to another tool, its columns are
+/// mapped then and only those are written, the table is never upgraded.
+fn is_adopted(config: &Config) -> bool {
+    config.file_name_column != "file_name"
+        || config.applied_at_column != "applied_at"
+        || config.version_column.is_some()
+}
+
+/// Version of the migrations table layout written by this build.
+const SCHEMA_VERSION: i64 = 7;
+
+/// Columns added to the migrations table over time with the schema version
+/// that introduced them. Version 1 only had `id` and `file_name`.
+const SCHEMA_UPGRADES: [(i64, &str, &str); 4] = [
+    (2, "applied_at", "TEXT"),
+    (3, "checksum", "TEXT"),
+    (4, "duration_ms", "INTEGER"),
+    (7, "tags", "TEXT"),
+];
+
+/// Fails when an existing migrations table doesn't look like one this
+/// migrator wrote, e.g. a table of another tool with the same name, so its
+/// rows are never read or written. `columns` are the columns of the table, a
+/// table that doesn't exist yet passes. An adopted table only needs the
+/// columns it was mapped to.
+fn check_table_schema(config: &Config, columns: &[String]) -> Result<(), AppError> {
+    if columns.is_empty() {
+        return Ok(());
+    }
+
+    let file_name = config.file_name_column.as_str();
+    let has = |column: &str| columns.iter().any(|c| c == column);
+
+    let (expected, compatible) = if is_adopted(config) {
+        let mut expected = vec![file_name, config.applied_at_column.as_str()];
+        expected.extend(config.version_column.as_deref());
+        let compatible = expected.iter().all(|column| has(column));
+        (expected, compatible)
+    } else {
+        let mut expected = vec!["id", file_name];
+        expected.extend(SCHEMA_UPGRADES.iter().map(|(_, column, _)| *column));
+        // tables of older versions miss the columns added since, those are added
+        let compatible = has(file_name) && columns.iter().all(|c| expected.contains(&c.as_str()));
+        (expected, compatible)
+    };
+    if compatible {
+        return Ok(());
+    }
+
+    Err(AppError::ConfigError(format!(
+        "table {} has the columns {} but the migrations table needs {}, it was probably created by another tool. Use another table with TRSO_TABLE_NAME or --table-name, or map its columns with TRSO_COLUMN_FILE_NAME, TRSO_COLUMN_APPLIED_AT and TRSO_COLUMN_VERSION",
+        config.table_name,
+        columns.join(", "),
+        expected.join(", ")
+    )))
+}
+
+/// The statement creating the migrations table in its current layout, when
+/// it doesn't exist yet. An adopted table only gets the mapped columns.
+fn migrations_table_sql(config: &Config) -> String {
+    let mut columns = vec![
+        String::from("id INTEGER PRIMARY KEY AUTOINCREMENT"),
+        format!("{} TEXT UNIQUE", config.file_name_column),
+        format!("{} TEXT NOT NULL DEFAULT (datetime('now'))", config.applied_at_column),
+    ];
+    if is_adopted(config) {
+        columns.extend(config.version_column.iter().map(|column| format!("{} INTEGER", column)));
+    } else {
+        let added = SCHEMA_UPGRADES.iter().filter(|(_, column, _)| *column != "applied_at");
+        columns.extend(added.map(|(_, column, definition)| format!("{} {}", column, definition)));
+    }
+
+    format!(
+        "CREATE TABLE IF NOT EXISTS {}\n(\n    {})",
+        config.table_name,
+        columns.join(",\n    ")
+    )
+}
+
+/// The statements creating the migrations table or upgrading it to the
+/// current layout, executed by `create_migrations_table` and written at the
+/// top of the `--output-sql` script. The database is only read.
+async fn table_upgrades(conn: &Connection, config: &Config) -> Result<Vec<String>, AppError> {
+    validate_columns(config)?;
+    let table = config.table_name.as_str();
+    let file_name = config.file_name_column.as_str();
+    let applied_at = config.applied_at_column.as_str();
+
+    let existing = table_columns(conn, table).await?;
+    check_table_schema(config, &existing)?;
+
+    let mut statements = vec![migrations_table_sql(config)];
+
+    // an adopted table keeps the layout of its tool, it's neither upgraded nor versioned
+    if is_adopted(config) {
+        return Ok(statements);
+    }
+
+    statements.push(String::from(
+        "CREATE TABLE IF NOT EXISTS migration_meta\n(\n    table_name TEXT PRIMARY KEY,\n    schema_version INTEGER NOT NULL)",
+    ));
+    let version = schema_version(conn, table).await?;
+    if version == Some(SCHEMA_VERSION) {
+        return Ok(statements);
+    }
+
+    if let Some(version) = version.filter(|version| *version > SCHEMA_VERSION) {
+        return Err(AppError::DatabaseError(format!(
+            "{} was upgraded to schema version {} by a newer trso-migrator, this one supports up to {}",
+            table, version, SCHEMA_VERSION
+        )));
+    }
+
+    // a table created by the first statement already has the current layout
+    if !existing.is_empty() {
+        // tables created by older versions miss the newer columns, SQLite can't
+        // add a column with a non-constant default so applied_at stays nullable there.
+        // Tables from before migration_meta existed have no version, their columns
+        // are checked one by one
+        for (since, column, definition) in SCHEMA_UPGRADES {
+            let column = if column == "applied_at" { applied_at } else { column };
+            if version.is_some_and(|version| since <= version)
+                || existing.iter().any(|c| c == column)
+            {
+                continue;
+            }
+
+            debug!("{} is missing the column {}", table, column);
+            statements.push(format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition));
+        }
+
+        // version 5 records the base name of `.up.sql` files, see `recorded_name`
+        if version.is_none_or(|version| version < 5) {
+            statements.push(format!(
+                "UPDATE {0} SET {1} = substr({1}, 1, length({1}) - 7) || '.sql' WHERE {1} LIKE '%.up.sql'",
+                table, file_name
+            ));
+        }
+
+        // version 6 made the file name unique
+        if version.is_none_or(|version| version < 6) {
+            let duplicates = duplicate_file_names(conn, config).await?;
+            if !duplicates.is_empty() {
+                return Err(AppError::DatabaseError(format!(
+                    "{} records {} more than once, delete the extra rows so {} can be made unique",
+                    table,
+                    duplicates.join(", "),
+                    file_name
+                )));
+            }
+
+            debug!("{} has no unique index on {}", table, file_name);
+            statements.push(format!(
+                "CREATE UNIQUE INDEX IF NOT EXISTS {0}_file_name ON {0} ({1})",
+                table, file_name
+            ));
+        }
+    }
+
+    // the table name is validated, it can be a literal
+    statements.push(format!(
+        "INSERT OR REPLACE INTO migration_meta (table_name, schema_version) VALUES ('{}', {})",
+        table, SCHEMA_VERSION
+    ));
+
+    Ok(statements)
+}
+
+async fn create_migrations_table(conn: &Connection, config: &Config) -> Result<(), AppError> {
+    for sql in table_upgrades(conn, config).await? {
+        if let Err(e) = conn.execute(&sql, ()).await {
+            return Err(AppError::DatabaseError(e.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// File names recorded more than once in `table`, which a unique index on
+/// `file_name` would refuse.
+async fn duplicate_file_names(conn: &Connection, config: &Config) -> Result<Vec<String>, AppError> {
+    let table = config.table_name.as_str();
+    let sql = format!(
+        "SELECT {1} FROM {0} WHERE {1} IS NOT NULL GROUP BY {1} HAVING COUNT(*) > 1 ORDER BY {1}",
+        table, config.file_name_column
+    );
+    let mut rows = match conn.query(&sql, ()).await {
+        Ok(rows) => rows,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let mut names = Vec::new();
+    loop {
+        let row = match rows.next().await {
+            Ok(Some(row)) => row,
+            Ok(None) => break,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+        if let Some(name) = text_column(&row, 0, table)? {
+            names.push(name);
+        }
+    }
+
+    Ok(names)
+}
+
+/// Schema version recorded for `table` in migration_meta, `None` for tables
+/// that were never upgraded by a version that keeps track of it.
+async fn schema_version(conn: &Connection, table: &str) -> Result<Option<i64>, AppError> {
+    if table_columns(conn, "migration_meta").await?.is_empty() {
+        return Ok(None);
+    }
+
+    let mut rows = match conn
+        .query(
+            "SELECT schema_version FROM migration_meta WHERE table_name = ?1",
+            [table],
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let row = match rows.next().await {
+        Ok(Some(row)) => row,
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    match row.get::<i64>(0) {
+        Ok(version) => Ok(Some(version)),
+        Err(e) => Err(AppError::DatabaseError(e.to_string())),
+    }
+}
+
+async fn table_columns(conn: &Connection, table: &str) -> Result<Vec<String>, AppError> {
+    let mut rows = match conn
+        .query(&format!("PRAGMA table_info({})", table), ())
+        .await
+    {
+        Ok(rows) => rows,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let mut columns = Vec::new();
+    loop {
+        let row = match rows.next().await {
+            Ok(Some(row)) => row,
+            Ok(None) => break,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+
+        match row.get::<String>(1) {
+            Ok(column) => columns.push(column),
+            Err(e) => {
+                return Err(AppError::DatabaseError(format!(
+                    "cannot read the columns of {}: {}",
+                    table, e
+                )))
+            }
+        }
+    }
+
+    Ok(columns)
+}
+
+/// A row of the migrations table.
+struct AppliedMigration {
+    applied_at: Option<String>,
+
+    checksum: Option<String>,
+}
+
+/// Loads the migrations table keyed by file name. Columns added by newer
+/// versions may be missing on old tables, those are read as NULL, and a table
+/// that doesn't exist yet has nothing applied.
+async fn load_applied_migrations(
+    conn: &Connection,
+    config: &Config,
+) -> Result<HashMap<String, AppliedMigration>, AppError> {
+    validate_columns(config)?;
+    let table = config.table_name.as_str();
+    let columns = table_columns(conn, table).await?;
+    check_table_schema(config, &columns)?;
+    if columns.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let column_or_null = |name: &str| {
+        if columns.iter().any(|c| c == name) {
+            name.to_string()
+        } else {
+            String::from("NULL")
+        }
+    };
+    // a checksum column of another tool holds other checksums
+    let checksum = if is_adopted(config) { String::from("NULL") } else { column_or_null("checksum") };
+    let sql = format!(
+        "SELECT {}, {}, {} FROM {}",
+        config.file_name_column,
+        column_or_null(&config.applied_at_column),
+        checksum,
+        table
+    );
+
+    let mut rows = match conn.query(&sql, ()).await {
+        Ok(rows) => rows,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let mut in_database = HashMap::new();
+    loop {
+        let row = match rows.next().await {
+            Ok(Some(row)) => row,
+            Ok(None) => break,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+
+        let name = match text_column(&row, 0, table)? {
+            Some(name) => name,
+            None => {
+                return Err(AppError::DatabaseError(format!(
+                    "{} contains a row without a file_name",
+                    table
+                )))
+            }
+        };
+        let applied_at = text_column(&row, 1, table)?;
+        let checksum = text_column(&row, 2, table)?;
+        // tables that weren't upgraded yet can still hold `.up.sql` names
+        in_database.insert(recorded_name(&name), AppliedMigration { applied_at, checksum });
+    }
+
+    Ok(in_database)
+}
+
+/// Reads a nullable text column, any other type is reported as a corrupt table.
+fn text_column(row: &libsql::Row, index: i32, table: &str) -> Result<Option<String>, AppError> {
+    let value = match row.get_value(index) {
+        Ok(value) => value,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    match value {
+        libsql::Value::Null => Ok(None),
+        libsql::Value::Text(text) => Ok(Some(text)),
+        _ => Err(AppError::DatabaseError(format!(
+            "unexpected value in column {} of {}, expected text",
+            index, table
+        ))),
+    }
+}
+
+/// Every `.sql` file of the source except the hooks, sorted by name.
+fn list_sql_files(
+    config: &Config,
+    source: &dyn MigrationSource,
+) -> Result<Vec<MigrationEntry>, AppError> {
+    let mut files = source.list()?;
+    files.retain(|entry| !is_hook(config, entry));
+    files.sort_by(|a, b| compare_names(&a.name, &b.name));
+
+    Ok(files)
+}
+
+/// Orders migration names by their leading number, so `2_users.sql` runs
+/// before `10_posts.sql` without zero padding. The rest of the name, and names
+/// without a number, are compared alphabetically.
+fn compare_names(a: &str, b: &str) -> Ordering {
+    let (a_number, a_rest) = split_number(a);
+    let (b_number, b_rest) = split_number(b);
+    if a_number.is_empty() || b_number.is_empty() {
+        return a.cmp(b);
+    }
+
+    // comparing the digits without leading zeros by length first can't overflow
+    let a_digits = a_number.trim_start_matches('0');
+    let b_digits = b_number.trim_start_matches('0');
+    a_digits
+        .len()
+        .cmp(&b_digits.len())
+        .then_with(|| a_digits.cmp(b_digits))
+        .then_with(|| a_rest.cmp(b_rest))
+        .then_with(|| a.cmp(b))
+}
+
+fn split_number(name: &str) -> (&str, &str) {
+    let end = name
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(name.len());
+
+    name.split_at(end)
+}
+
+/// The migration or rollback script with the given name.
+fn find_migration_file(
+    config: &Config,
+    source: &dyn MigrationSource,
+    name: &str,
+) -> Result<Option<MigrationEntry>, AppError> {
+    let entry = list_sql_files(config, source)?
+        .into_iter()
+        .find(|entry| entry.name == name);
+
+    Ok(entry)
+}
+
+/// Lists the forward migration files sorted in the order they are applied,
+/// named like they are recorded, see [`recorded_name`].
+fn list_migrations(
+    config: &Config,
+    source: &dyn MigrationSource,
+) -> Result<Vec<MigrationEntry>, AppError> {
+    let mut list_files = list_sql_files(config, source)?;
+
+    // rollback scripts are only executed by the down direction, verification
+    // queries after their migration
+    list_files.retain(|entry| {
+        !entry.name.ends_with(".down.sql") && !entry.name.ends_with(".verify.sql")
+    });
+
+    let mut files: HashMap<String, String> = HashMap::new();
+    for entry in list_files.iter_mut() {
+        let name = recorded_name(&entry.name);
+        if let Some(other) = files.insert(name.clone(), entry.name.clone()) {
+            return Err(AppError::IOError(format!(
+                "migration {} exists as both {} and {}",
+                name, other, entry.name
+            )));
+        }
+        entry.name = name;
+    }
+
+    Ok(list_files)
+}
+
+/// The forward migration recorded as `name`.
+fn find_migration(
+    config: &Config,
+    source: &dyn MigrationSource,
+    name: &str,
+) -> Result<Option<MigrationEntry>, AppError> {
+    let entry = list_migrations(config, source)?
+        .into_iter()
+        .find(|entry| entry.name == name);
+
+    Ok(entry)
+}
+
+/// Name a migration is recorded by, the `.up` of a `.up.sql` file is dropped
+/// so `001_users.up.sql` is recorded as `001_users.sql`.
+fn recorded_name(name: &str) -> String {
+    match name.strip_suffix(".up.sql") {
+        Some(base) => format!("{}.sql", base),
+        None => name.to_string(),
+    }
+}
+
+const BEFORE_HOOK: &str = "_before.sql";
+
+const AFTER_HOOK: &str = "_after.sql";
+
+/// Whether a listed file is one of the hooks, which are never treated as migrations.
+fn is_hook(config: &Config, entry: &MigrationEntry) -> bool {
+    if entry.name == BEFORE_HOOK || entry.name == AFTER_HOOK {
+        return true;
+    }
+
+    let file = &entry.location;
+    let same = |hook: &String| match (Path::new(hook).canonicalize(), file.canonicalize()) {
+        (Ok(hook), Ok(file)) => hook == file,
+        _ => Path::new(hook) == file,
+    };
+
+    config.before_hook.iter().chain(config.after_hook.iter()).any(same)
+}
+
+/// Executes a hook outside of any transaction so pragmas and `VACUUM` work,
+/// nothing is recorded. `configured` is a file on disk, otherwise `default_name`
+/// is looked up in the source. A missing hook is skipped.
+async fn run_hook(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    configured: &Option<String>,
+    default_name: &str,
+) -> Result<(), AppError> {
+    let entry = match configured {
+        Some(path) => Some(PathBuf::from(path))
+            .filter(|path| path.is_file())
+            .map(|path| MigrationEntry {
+                name: path.display().to_string(),
+                location: path,
+            }),
+        None => source
+            .list()?
+            .into_iter()
+            .find(|entry| entry.name == default_name),
+    };
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+
+    let name = entry.location.display().to_string();
+    if config.dry_run {
+        debug!("skipping hook {} in dry run", name);
+        return Ok(());
+    }
+
+    // hooks set by path always live on disk
+    let content = match configured {
+        Some(_) => match std::fs::read_to_string(&entry.location) {
+            Ok(content) => content,
+            Err(e) => return Err(AppError::IOError(e.to_string())),
+        },
+        None => source.content(&entry)?,
+    };
+
+    if let Err(e) = execute_sql(conn, &name, &content, config).await {
+        error!("Error while running hook {}", name);
+        return Err(e);
+    }
+    info!("Hook {} executed", name);
+
+    Ok(())
+}
+
+/// Default of `naming_pattern` when naming is enforced, e.g. `0001_create_users.sql`.
+pub const DEFAULT_NAMING_PATTERN: &str = r"^\d+_.+\.sql$";
+
+/// Checks the file name of every `.sql` file, rollback scripts included,
+/// against `naming_pattern` and lists all that don't match.
+fn check_naming(config: &Config, source: &dyn MigrationSource) -> Result<(), AppError> {
+    let pattern = match &config.naming_pattern {
+        Some(pattern) => pattern,
+        None => return Ok(()),
+    };
+
+    let regex = match regex::Regex::new(pattern) {
+        Ok(regex) => regex,
+        Err(e) => return Err(AppError::ConfigError(format!("invalid naming pattern: {}", e))),
+    };
+
+    let files = list_sql_files(config, source)?;
+
+    // subfolders are only for grouping, the pattern applies to the file itself
+    let invalid: Vec<String> = files
+        .into_iter()
+        .map(|entry| entry.name)
+        .filter(|name| !regex.is_match(name.rsplit('/').next().unwrap_or(name)))
+        .collect();
+
+    if invalid.is_empty() {
+        return Ok(());
+    }
+
+    Err(AppError::IOError(format!(
+        "migration file names don't match {}: {}",
+        pattern,
+        invalid.join(", ")
+    )))
+}
+
+/// Fails when two migrations share a leading number, e.g. `003_a.sql` and
+/// `003_b.sql`, since which one runs first is easy to get wrong.
+fn check_versions(config: &Config, source: &dyn MigrationSource) -> Result<(), AppError> {
+    if config.allow_duplicate_versions {
+        return Ok(());
+    }
+
+    let mut versions: HashMap<String, Vec<String>> = HashMap::new();
+    for entry in list_migrations(config, source)? {
+        let (number, _) = split_number(entry.name.rsplit('/').next().unwrap_or(&entry.name));
+        if number.is_empty() {
+            continue;
+        }
+
+        // 03 and 3 are the same version
+        let version = number.trim_start_matches('0').to_string();
+        versions.entry(version).or_default().push(entry.name);
+    }
+
+    let mut duplicates: Vec<String> = versions
+        .into_values()
+        .filter(|names| names.len() > 1)
+        .map(|names| names.join(", "))
+        .collect();
+
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+
+    duplicates.sort();
+    Err(AppError::IOError(format!(
+        "migrations share a version number: {}",
+        duplicates.join("; ")
+    )))
+}
+
+/// The `-- trso:<directive>` comments in the comment lines a migration starts
+/// with, e.g. `env staging`, `no-transaction`, `optional` or `tags billing`.
+fn directives(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(str::trim)
+        .take_while(|line| line.starts_with("--"))
+        .filter_map(|line| line.strip_prefix("--")?.trim_start().strip_prefix("trso:"))
+}
+
+/// The environment a migration is restricted to by a `-- trso:env <name>`
+/// line at its top.
+fn environment_tag(content: &str) -> Option<&str> {
+    directives(content).find_map(|directive| {
+        let tag = directive.strip_prefix("env")?;
+        if !tag.starts_with(char::is_whitespace) {
+            return None;
+        }
+
+        Some(tag.trim()).filter(|tag| !tag.is_empty())
+    })
+}
+
+/// Whether a migration opts out of its transaction with a
+/// `-- trso:no-transaction` line at its top.
+fn without_transaction(content: &str) -> bool {
+    directives(content).any(|directive| directive.trim() == "no-transaction")
+}
+
+/// The tags of a `-- trso:tags billing,reporting` line of a migration.
+fn migration_tags(content: &str) -> Vec<&str> {
+    directives(content)
+        .filter_map(|directive| {
+            let tags = directive.strip_prefix("tags")?;
+            tags.starts_with(char::is_whitespace).then_some(tags)
+        })
+        .flat_map(|tags| tags.split(','))
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// The tags of a migration as stored in the `tags` column, `None` without any.
+fn recorded_tags(content: &str) -> Option<String> {
+    let tags = migration_tags(content);
+    (!tags.is_empty()).then(|| tags.join(","))
+}
+
+/// Whether the failure of a migration is tolerated because of a
+/// `-- trso:optional` line at its top.
+fn is_optional(content: &str) -> bool {
+    directives(content).any(|directive| directive.trim() == "optional")
+}
+
+/// Hex encoded SHA-256 of the migration content.
+fn checksum(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// Returns the name of the `.down.sql` file paired with an applied migration,
+/// e.g. `001_users.up.sql` and `001_users.sql` both map to `001_users.down.sql`.
+fn down_file_name(name: &str) -> String {
+    let base = name
+        .strip_suffix(".up.sql")
+        .or_else(|| name.strip_suffix(".sql"))
+        .unwrap_or(name);
+
+    format!("{}.down.sql", base)
+}
+
+/// Fails with every migration that has no `.down.sql` file, e.g. as a check
+/// before merging. Only the migration files are read, returns how many were
+/// checked.
+pub fn check_down_files(
+    config: &Config,
+    source: &dyn MigrationSource,
+    pairing: DownPairing,
+) -> Result<usize, AppError> {
+    let files = list_sql_files(config, source)?;
+    let exists = |name: &str| files.iter().any(|entry| entry.name == name);
+
+    // the raw names, list_migrations already dropped the .up
+    let checked: Vec<&str> = files
+        .iter()
+        .map(|entry| entry.name.as_str())
+        .filter(|name| !name.ends_with(".down.sql") && !name.ends_with(".verify.sql"))
+        .filter(|name| pairing == DownPairing::All || name.ends_with(".up.sql"))
+        .collect();
+
+    let missing: Vec<&str> = checked
+        .iter()
+        .copied()
+        .filter(|name| !exists(&down_file_name(name)))
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(AppError::IOError(format!(
+            "migrations without a rollback file: {}",
+            missing.join(", ")
+        )));
+    }
+
+    Ok(checked.len())
+}
+
+/// Reads the `.verify.sql` query checked after a migration, e.g.
+/// `001_users.verify.sql` for `001_users.sql`. Returns its name and content.
+fn read_verify_file(
+    config: &Config,
+    source: &dyn MigrationSource,
+    name: &str,
+) -> Result<Option<(String, String)>, AppError> {
+    let verify_name = format!("{}.verify.sql", name.strip_suffix(".sql").unwrap_or(name));
+
+    match find_migration_file(config, source, &verify_name)? {
+        Some(entry) => Ok(Some((verify_name, source.content(&entry)?))),
+        None => Ok(None),
+    }
+}
+
+/// Runs the verification query of a migration, which fails unless it
+/// returns at least one row.
+async fn verify_migration(
+    conn: &Connection,
+    name: &str,
+    verify_name: &str,
+    query: &str,
+) -> Result<(), AppError> {
+    let mut rows = match conn.query(query, ()).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            return Err(AppError::DatabaseError(format!(
+                "verification {} of {} failed: {}",
+                verify_name, name, e
+            )))
+        }
+    };
+
+    match rows.next().await {
+        Ok(Some(_)) => {
+            debug!("Migration {} verified by {}", name, verify_name);
+            Ok(())
+        }
+        Ok(None) => Err(AppError::DatabaseError(format!(
+            "verification {} of {} returned no rows",
+            verify_name, name
+        ))),
+        Err(e) => Err(AppError::DatabaseError(format!(
+            "verification {} of {} failed: {}",
+            verify_name, name, e
+        ))),
+    }
+}
+
+/// The SQL of a migration with its `${VAR}` placeholders replaced when
+/// templating is on. The checksum stays the one of the file, the values may
+/// differ per environment.
+fn render(name: &str, content: &str, config: &Config) -> Result<String, AppError> {
+    if !config.template {
+        return Ok(content.to_string());
+    }
+
+    match template::render(content, |var| std::env::var(var).ok()) {
+        Ok(rendered) => Ok(rendered),
+        Err(missing) => Err(AppError::ConfigError(format!(
+            "migration {} uses variables that are not set: {}",
+            name,
+            missing.join(", ")
+        ))),
+    }
+}
+
+/// Executes the SQL of a migration file statement by statement, so a failure
+/// can name the statement that broke.
+async fn execute_sql(
+    conn: &Connection,
+    name: &str,
+    content: &str,
+    config: &Config,
+) -> Result<(), AppError> {
+    let rendered = render(name, content, config)?;
+    let content = rendered.as_str();
+    trace!("Executing {}:\n{}", name, content);
+
+    if config.statement_timeout_secs == 0 {
+        return run_sql(conn, content, config).await;
+    }
+
+    // the caller rolls back the transaction of a file that timed out. Local
+    // statements run without yielding and can't be aborted halfway, those
+    // are checked against the timeout once they finish
+    let timeout = Duration::from_secs(config.statement_timeout_secs);
+    let started = Instant::now();
+    match tokio::time::timeout(timeout, run_sql(conn, content, config)).await {
+        Ok(Ok(())) if started.elapsed() <= timeout => Ok(()),
+        Ok(Err(e)) => Err(e),
+        _ => Err(AppError::DatabaseError(format!(
+            "migration {} timed out after {:?}",
+            name, timeout
+        ))),
+    }
+}
+
+async fn run_sql(conn: &Connection, content: &str, config: &Config) -> Result<(), AppError> {
+    let statements = split::split_file(content);
+    if statements.is_empty() {
+        return Ok(());
+    }
+
+    // a batch can't be retried, the statements before the failing one already ran
+    if config.batch_execution {
+        return match conn.execute_batch(content).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(AppError::DatabaseError(e.to_string())),
+        };
+    }
+
+    for (index, statement) in statements.iter().enumerate() {
+        if let Err(e) = execute_retrying(conn, statement, config).await {
+            return Err(AppError::DatabaseError(format!(
+                "statement {}: {}",
+                index + 1,
+                e
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Executes a statement, retrying with a backoff for up to `lock_timeout_secs`
+/// while another connection holds a lock on the database.
+async fn execute_retrying(
+    conn: &Connection,
+    statement: &str,
+    config: &Config,
+) -> Result<(), libsql::Error> {
+    let deadline = Instant::now() + Duration::from_secs(config.lock_timeout_secs);
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        match conn.execute(statement, ()).await {
+            // statements such as PRAGMA journal_mode return a row, nothing to read
+            Ok(_) | Err(libsql::Error::ExecuteReturnedRows) => return Ok(()),
+            Err(e) if is_locked(&e.to_string()) && Instant::now() + backoff < deadline => {
+                debug!("database is busy, retrying in {:?}", backoff);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(2));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether an error message comes from contention with another connection
+/// rather than from the SQL itself.
+fn is_locked(message: &str) -> bool {
+    message.contains("database is locked")
+        || message.contains("database table is locked")
+        || message.contains("SQLITE_BUSY")
+        || message.contains("SQLITE_LOCKED")
+}
+
+/// Tells a database that stayed busy apart from a broken migration.
+fn explain_busy(e: AppError) -> AppError {
+    match e {
+        AppError::MigrationFailed { file, source } => AppError::MigrationFailed {
+            file,
+            source: Box::new(explain_busy(*source)),
+        },
+        AppError::DatabaseError(message)
+            if is_locked(&message) && !message.starts_with("the database is busy") =>
+        {
+            AppError::DatabaseError(format!(
+                "the database is busy, another connection holds a lock ({}). Retry later or wait longer with --lock-timeout",
+                message
+            ))
+        }
+        e => e,
+    }
+}
+
+/// Appends the statements of a migration and the insert recording it to the
+/// script of `Config::output_sql`, rendered like they would be executed.
+fn compile_migration(
+    script: &mut String,
+    name: &str,
+    content: &str,
+    config: &Config,
+) -> Result<(), AppError> {
+    if without_transaction(content) {
+        return Err(AppError::ConfigError(format!(
+            "migration {} runs without a transaction, which the compiled script can't do",
+            name
+        )));
+    }
+
+    script.push_str(&format!("\n-- {}\n", name));
+    for statement in split::split_file(&render(name, content, config)?) {
+        // a trailing line comment would swallow the semicolon
+        let last_line = statement.lines().last().unwrap_or_default();
+        let separator = if last_line.contains("--") { "\n;" } else { ";" };
+        script.push_str(&format!("{}{}\n", statement, separator));
+    }
+    let columns = record_columns(config, name, content, None);
+    let literals: Vec<String> = columns
+        .iter()
+        .map(|(_, value)| match value {
+            libsql::Value::Text(text) => sql_literal(Some(text)),
+            libsql::Value::Integer(number) => number.to_string(),
+            _ => String::from("NULL"),
+        })
+        .collect();
+    script.push_str(&format!("{};\n", record_sql(config, &columns, &literals)));
+
+    Ok(())
+}
+
+/// A string as an SQL literal, `NULL` for `None`.
+fn sql_literal(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("'{}'", value.replace('\'', "''")),
+        None => String::from("NULL"),
+    }
+}
+
+/// Applies a migration in a transaction of its own, which is rolled back when
+/// it fails.
+async fn apply_in_transaction(
+    conn: &Connection,
+    name: &str,
+    content: &str,
+    config: &Config,
+) -> Result<Duration, AppError> {
+    let transaction = match conn.transaction().await {
+        Ok(transaction) => transaction,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+    match apply_migration(&transaction, name, content, config).await {
+        Ok(duration) => match transaction.commit().await {
+            Ok(_) => Ok(duration),
+            Err(e) => Err(AppError::DatabaseError(e.to_string())),
+        },
+        Err(e) => {
+            let _ = transaction.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+/// Whether an error message comes from the connection to a remote database
+/// rather than from the SQL, so running the file again may succeed. Syntax
+/// and constraint errors never match, neither do object names that only
+/// contain one of the words, so the patterns are whole phrases.
+fn is_transient(message: &str) -> bool {
+    let message = message.to_lowercase();
+    [
+        // Hrana streams the server dropped, e.g. after a period of inactivity
+        "stream closed",
+        "stream expired",
+        "stream has expired",
+        "connection reset",
+        "connection closed",
+        "connection refused",
+        "broken pipe",
+        "connection timed out",
+        "operation timed out",
+        "502 bad gateway",
+        "503 service unavailable",
+        "504 gateway timeout",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
+
+/// Applies an optional migration inside the single transaction behind a
+/// savepoint, a failure rolls back to it and leaves the earlier files applied.
+/// Its `verify` query runs behind the same savepoint and fails it the same way.
+async fn apply_in_savepoint(
+    conn: &Connection,
+    name: &str,
+    content: &str,
+    verify: Option<&(String, String)>,
+    config: &Config,
+) -> Result<Duration, AppError> {
+    if let Err(e) = conn.execute("SAVEPOINT trso_optional", ()).await {
+        return Err(AppError::DatabaseError(e.to_string()));
+    }
+
+    let mut result = apply_migration(conn, name, content, config).await;
+    if let (Ok(_), Some((verify_name, query))) = (&result, verify) {
+        if let Err(e) = verify_migration(conn, name, verify_name, query).await {
+            result = Err(e);
+        }
+    }
+    if result.is_err() {
+        let rollback = conn.execute("ROLLBACK TO SAVEPOINT trso_optional", ()).await;
+        if let Err(e) = rollback {
+            return Err(AppError::DatabaseError(e.to_string()));
+        }
+    }
+    if let Err(e) = conn.execute("RELEASE SAVEPOINT trso_optional", ()).await {
+        return Err(AppError::DatabaseError(e.to_string()));
+    }
+
+    result
+}
+
+/// The error of recording `name`, a unique constraint violation means the file
+/// was recorded since the applied migrations were loaded.
+fn record_error(e: libsql::Error, name: &str, table: &str) -> AppError {
+    let message = e.to_string();
+    if message.contains("UNIQUE constraint failed") {
+        return AppError::DatabaseError(format!(
+            "migration {} is already recorded in {}, another run may have applied it meanwhile",
+            name, table
+        ));
+    }
+
+    AppError::DatabaseError(message)
+}
+
+/// Executes the migration and records it in the migrations table together
+/// with how long it took, the caller owns the surrounding transaction.
+async fn apply_migration(
+    conn: &Connection,
+    name: &str,
+    content: &str,
+    config: &Config,
+) -> Result<Duration, AppError> {
+    let started = Instant::now();
+    execute_sql(conn, name, content, config).await?;
+    let duration = started.elapsed();
+
+    match record_migration(conn, config, name, content, Some(duration)).await {
+        Ok(_) => Ok(duration),
+        Err(e) => Err(record_error(e, name, &config.table_name)),
+    }
+}
+
+/// The columns a migration is recorded with besides the applied_at one, which
+/// is `datetime('now')`. An adopted table only gets the columns it was mapped
+/// to, the migrator's own one also the checksum, the tags and, for an executed
+/// file, how long it took.
+fn record_columns<'a>(
+    config: &'a Config,
+    name: &str,
+    content: &str,
+    duration: Option<Duration>,
+) -> Vec<(&'a str, libsql::Value)> {
+    let mut columns = vec![(config.file_name_column.as_str(), libsql::Value::from(name))];
+    if let Some(column) = &config.version_column {
+        let (number, _) = split_number(name.rsplit('/').next().unwrap_or(name));
+        let version = (!number.is_empty()).then_some(number);
+        columns.push((column.as_str(), libsql::Value::from(version)));
+    }
+    if is_adopted(config) {
+        return columns;
+    }
+
+    columns.push(("checksum", libsql::Value::from(checksum(content))));
+    if let Some(duration) = duration {
+        columns.push(("duration_ms", libsql::Value::from(duration.as_millis() as i64)));
+    }
+    columns.push(("tags", libsql::Value::from(recorded_tags(content))));
+    columns
+}
+
+/// The insert of `columns` into the migrations table, `values` are their
+/// placeholders or literals.
+fn record_sql(config: &Config, columns: &[(&str, libsql::Value)], values: &[String]) -> String {
+    let names: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+    format!(
+        "INSERT INTO {} ({}, {}) VALUES ({}, datetime('now'))",
+        config.table_name,
+        names.join(", "),
+        config.applied_at_column,
+        values.join(", ")
+    )
+}
+
+/// Records `name` as applied, see `record_columns` for what is written.
+async fn record_migration(
+    conn: &Connection,
+    config: &Config,
+    name: &str,
+    content: &str,
+    duration: Option<Duration>,
+) -> Result<u64, libsql::Error> {
+    let columns = record_columns(config, name, content, duration);
+    let placeholders: Vec<String> = (1..=columns.len()).map(|i| format!("?{}", i)).collect();
+    let sql = record_sql(config, &columns, &placeholders);
+    let values: Vec<libsql::Value> = columns.into_iter().map(|(_, value)| value).collect();
+
+    conn.execute(&sql, values).await
+}
+
+/// Applies the pending migrations of `path` with the default settings.
+pub async fn migrate(conn: &Connection, path: &str) -> Result<MigrationReport, AppError> {
+    let config = Config {
+        migrations_path: path.to_string(),
+        ..Default::default()
+    };
+
+    migrate_database(conn, &config, &FsSource::from_config(&config)).await
+}
+
+/// Claims the single row of the migration_lock table so concurrent runs don't
+/// race each other, returns the owner id to release the lock with.
+async fn acquire_lock(conn: &Connection, config: &Config) -> Result<String, AppError> {
+    let result = conn
+        .execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS migration_lock
+            (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                owner TEXT NOT NULL,
+                locked_at TEXT NOT NULL DEFAULT (datetime('now')));
+        "#,
+            (),
+        )
+        .await;
+
+    if let Err(e) = result {
+        return Err(AppError::DatabaseError(e.to_string()));
+    }
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos();
+    let owner = format!("{}-{}", std::process::id(), nanos);
+
+    let deadline = Instant::now() + Duration::from_secs(config.lock_timeout_secs);
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        // the insert is atomic, only one run can create the row
+        let claimed = match conn
+            .execute(
+                "INSERT OR IGNORE INTO migration_lock (id, owner) VALUES (1, ?1)",
+                [owner.as_str()],
+            )
+            .await
+        {
+            Ok(changed) => changed == 1,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+
+        if claimed {
+            return Ok(owner);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(AppError::LockTimeout(Duration::from_secs(config.lock_timeout_secs)));
+        }
+
+        warn!("migration lock is held by another run, waiting ...");
+        tokio::time::sleep(backoff.min(deadline - now)).await;
+        backoff = (backoff * 2).min(Duration::from_secs(2));
+    }
+}
+
+async fn release_lock(conn: &Connection, owner: &str) {
+    if let Err(e) = conn
+        .execute("DELETE FROM migration_lock WHERE id = 1 AND owner = ?1", [owner])
+        .await
+    {
+        warn!("cannot release the migration lock {}", e);
+    }
+}
+
+/// Runs `task` holding the migration lock. Dry runs never write migrations so
+/// they don't compete for it, and user_version runs are serialized by their
+/// immediate transactions, neither takes it.
+async fn with_lock<T>(
+    conn: &Connection,
+    config: &Config,
+    task: impl std::future::Future<Output = Result<T, AppError>>,
+) -> Result<T, AppError> {
+    validate_table_name(&config.table_name)?;
+
+    if config.dry_run || config.tracking == Tracking::UserVersion {
+        return task.await;
+    }
+
+    let owner = acquire_lock(conn, config).await.map_err(explain_busy)?;
+    let result = task.await.map_err(explain_busy);
+    release_lock(conn, &owner).await;
+
+    result
+}
+
+/// Applies every pending migration of `source`, in dry run mode
+/// nothing is executed and the pending files are reported as applied.
+pub async fn migrate_database(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+) -> Result<MigrationReport, AppError> {
+    let mut results = Vec::new();
+    let result = with_lock(conn, config, run_with_hooks(conn, config, source, &mut results)).await;
+
+    if config.output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&results).unwrap());
+    }
+
+    result
+}
+
+async fn run_with_hooks(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    results: &mut Vec<FileResult>,
+) -> Result<MigrationReport, AppError> {
+    // nothing, not even the hook, runs when the files themselves are invalid
+    check_naming(config, source)?;
+    check_versions(config, source)?;
+
+    run_hook(conn, config, source, &config.before_hook, BEFORE_HOOK).await?;
+    let report = match config.tracking {
+        Tracking::Table => run_migrations(conn, config, source, results).await?,
+        Tracking::UserVersion => run_user_version(conn, config, source, results).await?,
+    };
+    run_hook(conn, config, source, &config.after_hook, AFTER_HOOK).await?;
+
+    Ok(report)
+}
+
+/// Applies the files numbered above `PRAGMA user_version` in order, each one
+/// in an immediate transaction that also sets `user_version` to its number.
+/// Nothing else is stored, so a file changed after it was applied goes
+/// unnoticed.
+async fn run_user_version(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    results: &mut Vec<FileResult>,
+) -> Result<MigrationReport, AppError> {
+    let mut versions: Vec<(i32, MigrationEntry)> = Vec::new();
+    for entry in list_migrations(config, source)? {
+        let (number, _) = split_number(entry.name.rsplit('/').next().unwrap_or(&entry.name));
+        let version = match number.parse::<i32>() {
+            Ok(version) => version,
+            Err(_) => {
+                return Err(AppError::IOError(format!(
+                    "migration {} needs a number prefix up to {} to be tracked in user_version",
+                    entry.name,
+                    i32::MAX
+                )))
+            }
+        };
+        if let Some((previous, other)) = versions.last() {
+            if version <= *previous {
+                return Err(AppError::IOError(format!(
+                    "migration {} has the version {}, it has to be above the version {} of {}",
+                    entry.name, version, previous, other.name
+                )));
+            }
+        }
+        versions.push((version, entry));
+    }
+
+    let mut report = MigrationReport::default();
+    for (version, entry) in versions {
+        if config.cancel.load(AtomicOrdering::SeqCst) {
+            return Err(AppError::Interrupted(format!(
+                "stopped before {}, {} migration(s) were applied",
+                entry.name,
+                report.applied.len()
+            )));
+        }
+
+        let name = entry.name.clone();
+        if version <= user_version(conn).await? {
+            debug!("skipping file {}, it is already applied", name);
+            report.skipped.push(name.clone());
+            results.push(FileResult {
+                name,
+                status: FileStatus::Skipped,
+                duration_ms: None,
+                error: None,
+            });
+            continue;
+        }
+
+        let content = source.content(&entry)?;
+        if config.dry_run {
+            if config.output == OutputFormat::Text {
+                println!("Pending migration {}:", name);
+                println!("{}", content);
+            }
+            report.applied.push(name.clone());
+            results.push(FileResult {
+                name,
+                status: FileStatus::Pending,
+                duration_ms: None,
+                error: None,
+            });
+            continue;
+        }
+
+        let started = Instant::now();
+        match apply_versioned(conn, &name, &content, version, config).await {
+            Ok(true) => {
+                let message = format!(
+                    "Migration applied for file {} in {:.2?}, user_version is {}",
+                    name,
+                    started.elapsed(),
+                    version
+                );
+                info!("{}", color::log(config, Color::Green, message));
+                report.applied.push(name.clone());
+                results.push(FileResult {
+                    name,
+                    status: FileStatus::Applied,
+                    duration_ms: Some(started.elapsed().as_millis()),
+                    error: None,
+                });
+            }
+            // another run got to it between the check and the transaction
+            Ok(false) => {
+                report.skipped.push(name.clone());
+                results.push(FileResult {
+                    name,
+                    status: FileStatus::Skipped,
+                    duration_ms: None,
+                    error: None,
+                });
+            }
+            Err(e) => {
+                error!("Error while executing migration {}", name);
+                results.push(FileResult {
+                    name: name.clone(),
+                    status: FileStatus::Failed,
+                    duration_ms: Some(started.elapsed().as_millis()),
+                    error: Some(e.to_string()),
+                });
+                return Err(AppError::MigrationFailed { file: name, source: Box::new(e) });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Runs one migration of [`run_user_version`] and sets `user_version` to
+/// `version` in the same transaction. Returns false without running it when
+/// `user_version` already reached `version`.
+async fn apply_versioned(
+    conn: &Connection,
+    name: &str,
+    content: &str,
+    version: i32,
+    config: &Config,
+) -> Result<bool, AppError> {
+    let transaction = match conn.transaction_with_behavior(TransactionBehavior::Immediate).await {
+        Ok(transaction) => transaction,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let result = match user_version(&transaction).await {
+        Ok(current) if current >= version => Ok(false),
+        Ok(_) => match execute_sql(&transaction, name, content, config).await {
+            Ok(_) => {
+                let sql = format!("PRAGMA user_version = {}", version);
+                match transaction.execute(&sql, ()).await {
+                    Ok(_) => Ok(true),
+                    Err(e) => Err(AppError::DatabaseError(e.to_string())),
+                }
+            }
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(applied) => match transaction.commit().await {
+            Ok(_) => Ok(applied),
+            Err(e) => Err(AppError::DatabaseError(e.to_string())),
+        },
+        Err(e) => {
+            let _ = transaction.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+async fn user_version(conn: &Connection) -> Result<i32, AppError> {
+    let mut rows = match conn.query("PRAGMA user_version", ()).await {
+        Ok(rows) => rows,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    match rows.next().await {
+        Ok(Some(row)) => match row.get::<i32>(0) {
+            Ok(version) => Ok(version),
+            Err(e) => Err(AppError::DatabaseError(e.to_string())),
+        },
+        Ok(None) => Ok(0),
+        Err(e) => Err(AppError::DatabaseError(e.to_string())),
+    }
+}
+
+/// Moves the record of an applied migration whose file no longer exists to
+/// the pending file with the same content, so a renamed file is recognized as
+/// applied. In dry run mode the table is left as it is.
+async fn follow_renames(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    list_files: &[MigrationEntry],
+    in_database: &mut HashMap<String, AppliedMigration>,
+) -> Result<(), AppError> {
+    let exists = |name: &str| list_files.iter().any(|entry| entry.name == name);
+
+    for entry in list_files {
+        if in_database.contains_key(&entry.name) {
+            continue;
+        }
+
+        let sum = checksum(&source.content(entry)?);
+        let old_name = in_database
+            .iter()
+            .find(|(name, applied)| applied.checksum.as_deref() == Some(&sum) && !exists(name))
+            .map(|(name, _)| name.clone());
+        let old_name = match old_name {
+            Some(old_name) => old_name,
+            None => continue,
+        };
+
+        warn!(
+            "migration {} has the content of the applied migration {}, recording it as renamed",
+            entry.name, old_name
+        );
+        if !config.dry_run {
+            let sql = format!(
+                "UPDATE {0} SET {1} = ?1 WHERE {1} = ?2",
+                config.table_name, config.file_name_column
+            );
+            let params = libsql::params![entry.name.as_str(), old_name.as_str()];
+            if let Err(e) = conn.execute(&sql, params).await {
+                return Err(AppError::DatabaseError(e.to_string()));
+            }
+        }
+
+        if let Some(applied) = in_database.remove(&old_name) {
+            in_database.insert(entry.name.clone(), applied);
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the record of an applied migration that was stored by its plain file
+/// name to the relative path of the one file in a subfolder with that name,
+/// so moving files into subfolders keeps them applied. In dry run mode the
+/// table is left as it is.
+async fn follow_moves(
+    conn: &Connection,
+    config: &Config,
+    list_files: &[MigrationEntry],
+    in_database: &mut HashMap<String, AppliedMigration>,
+) -> Result<(), AppError> {
+    let mut moved = Vec::new();
+    for old_name in in_database.keys() {
+        if old_name.contains('/') || list_files.iter().any(|entry| entry.name == *old_name) {
+            continue;
+        }
+
+        let suffix = format!("/{}", old_name);
+        let mut candidates = list_files.iter().filter(|entry| entry.name.ends_with(&suffix));
+        if let (Some(entry), None) = (candidates.next(), candidates.next()) {
+            if !in_database.contains_key(&entry.name) {
+                moved.push((old_name.clone(), entry.name.clone()));
+            }
+        }
+    }
+
+    for (old_name, new_name) in moved {
+        warn!(
+            "applied migration {} is now {}, recording it under its relative path",
+            old_name, new_name
+        );
+        if !config.dry_run {
+            let sql = format!(
+                "UPDATE {0} SET {1} = ?1 WHERE {1} = ?2",
+                config.table_name, config.file_name_column
+            );
+            let params = libsql::params![new_name.as_str(), old_name.as_str()];
+            if let Err(e) = conn.execute(&sql, params).await {
+                return Err(AppError::DatabaseError(e.to_string()));
+            }
+        }
+
+        if let Some(applied) = in_database.remove(&old_name) {
+            in_database.insert(new_name, applied);
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_migrations(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    results: &mut Vec<FileResult>,
+) -> Result<MigrationReport, AppError> {
+    let mut list_files = list_migrations(config, source)?;
+
+    // a dry run only reads the applied migrations, it neither creates nor
+    // upgrades the table
+    if !config.dry_run {
+        create_migrations_table(conn, config).await?;
+    }
+    let mut in_database = load_applied_migrations(conn, config).await?;
+    if config.recursive {
+        follow_moves(conn, config, &list_files, &mut in_database).await?;
+    }
+    if config.match_checksums {
+        follow_renames(conn, config, source, &list_files, &mut in_database).await?;
+    }
+
+    // a deleted file hides that environments may have diverged
+    let mut missing: Vec<&str> = in_database
+        .keys()
+        .map(|name| name.as_str())
+        .filter(|name| !list_files.iter().any(|entry| entry.name == *name))
+        .collect();
+    missing.sort();
+
+    for name in &missing {
+        warn!("applied migration {} no longer exists", name);
+    }
+
+    if config.strict && !missing.is_empty() {
+        return Err(AppError::IOError(format!(
+            "applied migrations {} are missing",
+            missing.join(", ")
+        )));
+    }
+
+    if let Some(only) = &config.only {
+        let only = recorded_name(only);
+        list_files.retain(|entry| entry.name == only);
+        if list_files.is_empty() {
+            return Err(AppError::IOError(format!("migration {} does not exist", only)));
+        }
+    }
+
+    if let Some(since) = &config.since {
+        let since = recorded_name(since);
+        let position = match list_files.iter().position(|entry| entry.name == since) {
+            Some(position) => position,
+            None => {
+                return Err(AppError::IOError(format!(
+                    "since migration {} does not exist",
+                    since
+                )))
+            }
+        };
+
+        list_files.drain(..=position);
+    }
+
+    if let Some(target) = &config.target {
+        let target = recorded_name(target);
+        let position = match list_files.iter().position(|entry| entry.name == target) {
+            Some(position) => position,
+            None => {
+                return Err(AppError::IOError(format!(
+                    "target migration {} does not exist",
+                    target
+                )))
+            }
+        };
+
+        if in_database.contains_key(&target) {
+            info!("Target migration {} is already applied, nothing to do", target);
+            return Ok(MigrationReport::default());
+        }
+
+        list_files.truncate(position + 1);
+    }
+
+    // a tagged file is applied in order, so a pending file without the tag
+    // sorting before it has to be applied first. Files of other environments
+    // stay pending anyway
+    if let Some(tag) = &config.tag {
+        let mut untagged: Vec<String> = Vec::new();
+        let mut kept = Vec::new();
+        for entry in list_files {
+            if in_database.contains_key(&entry.name) {
+                kept.push(entry);
+                continue;
+            }
+
+            let content = source.content(&entry)?;
+            if migration_tags(&content).contains(&tag.as_str()) {
+                if !untagged.is_empty() {
+                    return Err(AppError::IOError(format!(
+                        "migrations {} are pending and sort before {} of tag {}, apply them first",
+                        untagged.join(", "),
+                        entry.name,
+                        tag
+                    )));
+                }
+                kept.push(entry);
+            } else if environment_tag(&content).is_none_or(|env| {
+                config.environment.as_deref() == Some(env)
+            }) {
+                untagged.push(entry.name);
+            }
+        }
+        list_files = kept;
+    }
+
+    // files of other environments don't use up the count
+    let mut held_back = 0;
+    if let Some(count) = config.count {
+        let mut pending = Vec::new();
+        for (index, entry) in list_files.iter().enumerate() {
+            if in_database.contains_key(&entry.name) {
+                continue;
+            }
+            match environment_tag(&source.content(entry)?) {
+                Some(tag) if config.environment.as_deref() != Some(tag) => (),
+                _ => pending.push(index),
+            }
+        }
+
+        if pending.len() > count {
+            held_back = pending.len() - count;
+            list_files.truncate(pending[count]);
+        }
+    }
+
+    // a pending file sorting before the latest applied one usually comes from a
+    // branch that was merged after newer migrations were already applied
+    if let Some(latest) = in_database.keys().max_by(|a, b| compare_names(a, b)) {
+        let mut out_of_order: Vec<&str> = Vec::new();
+        for entry in &list_files {
+            let name = entry.name.as_str();
+            if in_database.contains_key(name) || compare_names(name, latest) != Ordering::Less {
+                continue;
+            }
+
+            // files of other environments stay pending on purpose
+            let content = source.content(entry)?;
+            match environment_tag(&content) {
+                Some(tag) if config.environment.as_deref() != Some(tag) => (),
+                _ => out_of_order.push(name),
+            }
+        }
+
+        for name in &out_of_order {
+            warn!(
+                "pending migration {} sorts before the latest applied migration {}",
+                name, latest
+            );
+        }
+
+        if config.strict_order && !out_of_order.is_empty() {
+            return Err(AppError::DatabaseError(format!(
+                "migrations {} are out of order, the latest applied migration is {}",
+                out_of_order.join(", "),
+                latest
+            )));
+        }
+    }
+
+    // in single transaction mode every pending file shares this transaction,
+    // with commit_every it is committed and replaced after that many files
+    let mut batch = if config.single_transaction && !config.dry_run {
+        match conn.transaction().await {
+            Ok(transaction) => Some(transaction),
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        }
+    } else {
+        None
+    };
+
+    // dry runs show what the pending files change on a copy of the schema
+    let preview = if config.dry_run
+        && config.output == OutputFormat::Text
+        && config.output_sql.is_none()
+    {
+        match preview::SchemaPreview::new(conn).await {
+            Ok(preview) => Some(preview),
+            Err(e) => {
+                warn!("cannot preview the schema changes {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // applied files are numbered out of the pending ones, e.g. `[3/12]`,
+    // files of other environments are skipped and not counted
+    let mut pending = 0;
+    for entry in list_files.iter().filter(|entry| !in_database.contains_key(&entry.name)) {
+        let content = source.content(entry)?;
+        if environment_tag(&content).is_none_or(|tag| config.environment.as_deref() == Some(tag)) {
+            pending += 1;
+        }
+    }
+    let mut position = 0;
+
+    // results before this index belong to committed batches
+    let mut committed = results.len();
+    let mut in_batch = 0;
+    // every file of the batches so far, in_batch restarts at each commit
+    let mut batched = 0;
+
+    let mut migration_content: String;
+    let mut report = MigrationReport::default();
+    let mut script = String::new();
+    for entry in list_files {
+        if config.cancel.load(AtomicOrdering::SeqCst) {
+            // nothing of a single transaction is kept when it is cut short
+            if let Some(batch) = batch {
+                let _ = batch.rollback().await;
+                for result in results.iter_mut().skip(committed) {
+                    if result.status == FileStatus::Applied {
+                        result.status = FileStatus::RolledBack;
+                    }
+                }
+                return Err(AppError::Interrupted(format!(
+                    "the single transaction was rolled back, {} migration(s) were committed before",
+                    report.applied.len() - in_batch
+                )));
+            }
+
+            return Err(AppError::Interrupted(format!(
+                "stopped before {}, {} migration(s) were applied",
+                entry.name,
+                report.applied.len()
+            )));
+        }
+
+        let name = entry.name.clone();
+        if let Some(stored) = in_database.get(&name) {
+            // rows from older versions have no checksum to compare against
+            match &stored.checksum {
+                Some(stored) if !config.skip_checksum_verify => {
+                    let content = source.content(&entry)?;
+                    if *stored != checksum(&content) {
+                        return Err(AppError::DatabaseError(format!(
+                            "migration {} was changed after it was applied, checksum does not match",
+                            name
+                        )));
+                    }
+                }
+                _ => (),
+            }
+
+            debug!("skipping file {}, it is already applied", name);
+            report.skipped.push(name.clone());
+            results.push(FileResult {
+                name,
+                status: FileStatus::Skipped,
+                duration_ms: None,
+                error: None,
+            });
+            continue;
+        }
+
+        migration_content = source.content(&entry)?;
+
+        if let Some(tag) = environment_tag(&migration_content) {
+            if config.environment.as_deref() != Some(tag) {
+                let message = format!("skipping file {}, it only runs in the {} environment", name, tag);
+                info!("{}", color::log(config, Color::Yellow, message));
+                report.skipped_env.push(name.clone());
+                results.push(FileResult {
+                    name,
+                    status: FileStatus::SkippedEnv,
+                    duration_ms: None,
+                    error: None,
+                });
+                continue;
+            }
+        }
+        position += 1;
+
+        if config.dry_run {
+            if config.output_sql.is_some() {
+                compile_migration(&mut script, &name, &migration_content, config)?;
+            } else if config.output == OutputFormat::Text {
+                println!("Pending migration {}:", name);
+                println!("{}", migration_content);
+
+                if let Some(preview) = &preview {
+                    match preview.apply(&name, &migration_content, config).await {
+                        Ok(changes) => {
+                            println!("Schema changes of {}:", name);
+                            for change in changes {
+                                println!("{}", change);
+                            }
+                        }
+                        Err(e) => warn!("cannot preview the schema changes of {} {}", name, e),
+                    }
+                }
+            }
+            report.applied.push(name.clone());
+            results.push(FileResult {
+                name,
+                status: FileStatus::Pending,
+                duration_ms: None,
+                error: None,
+            });
+            continue;
+        }
+
+        // an empty or comment-only file still counts as applied, a no-op
+        if split::split_file(&migration_content).is_empty() {
+            info!("Migration {} has no statements, recording it as applied", name);
+        }
+
+        let no_transaction = without_transaction(&migration_content);
+        let optional = is_optional(&migration_content);
+        let verify = read_verify_file(config, source, &name)?;
+        let started = Instant::now();
+        let result = match &batch {
+            Some(_) if no_transaction => Err(AppError::ConfigError(format!(
+                "migration {} runs without a transaction, which single transaction mode can't do",
+                name
+            ))),
+            Some(batch) if optional => {
+                apply_in_savepoint(batch, &name, &migration_content, verify.as_ref(), config).await
+            }
+            Some(batch) => apply_migration(batch, &name, &migration_content, config).await,
+            // not atomic, a failure can leave part of the file applied
+            None if no_transaction => {
+                apply_migration(conn, &name, &migration_content, config).await
+            }
+            None => {
+                let mut backoff = Duration::from_millis(500);
+                let mut attempt = 0;
+                loop {
+                    match apply_in_transaction(conn, &name, &migration_content, config).await {
+                        Err(e) if attempt < config.exec_retries && is_transient(&e.to_string()) => {
+                            attempt += 1;
+                            warn!(
+                                "Migration {} failed with a transient error ({}), retrying in {:?} ({}/{})",
+                                name, e, backoff, attempt, config.exec_retries
+                            );
+                            tokio::time::sleep(backoff).await;
+                            backoff *= 2;
+                        }
+                        result => break result,
+                    }
+                }
+            }
+        };
+
+        // the failed file is already undone, only its savepoint or its own
+        // transaction was rolled back
+        let result = match result {
+            Err(e) if optional => {
+                let message = format!("optional migration {} failed, leaving it pending: {}", name, e);
+                warn!("{}", color::log(config, Color::Yellow, message));
+                report.failed_optional.push(name.clone());
+                results.push(FileResult {
+                    name,
+                    status: FileStatus::FailedOptional,
+                    duration_ms: Some(started.elapsed().as_millis()),
+                    error: Some(e.to_string()),
+                });
+                continue;
+            }
+            result => result,
+        };
+
+        // checked once the file committed, in single transaction mode a failure
+        // still rolls back everything. Optional files were verified behind their
+        // savepoint already
+        let result = match (result, &verify) {
+            (Ok(duration), Some((verify_name, query))) if !(optional && batch.is_some()) => {
+                let verify_conn: &Connection = match &batch {
+                    Some(batch) => batch,
+                    None => conn,
+                };
+                match verify_migration(verify_conn, &name, verify_name, query).await {
+                    Ok(_) => Ok(duration),
+                    Err(e) => {
+                        if batch.is_none() {
+                            warn!(
+                                "migration {} stays applied although it failed its verification",
+                                name
+                            );
+                        }
+                        Err(e)
+                    }
+                }
+            }
+            (result, _) => result,
+        };
+
+        match result {
+            Ok(duration) => {
+                // a COMMIT inside the file ends the shared transaction early and
+                // everything applied so far is already persisted
+                if batch.as_ref().is_some_and(|batch| batch.is_autocommit()) {
+                    return Err(AppError::DatabaseError(format!(
+                        "migration {} committed the single transaction, migrations up to and including it are already applied",
+                        name
+                    )));
+                }
+
+                let message = format!(
+                    "[{}/{}] Migration applied for file {} in {:.2?}",
+                    position, pending, name, duration
+                );
+                info!("{}", color::log(config, Color::Green, message));
+                report.applied.push(name.clone());
+                results.push(FileResult {
+                    name,
+                    status: FileStatus::Applied,
+                    duration_ms: Some(duration.as_millis()),
+                    error: None,
+                });
+
+                in_batch += 1;
+                batched += 1;
+                if config.commit_every > 0 && in_batch == config.commit_every {
+                    if let Some(full) = batch.take() {
+                        if let Err(e) = full.commit().await {
+                            return Err(AppError::DatabaseError(e.to_string()));
+                        }
+                        debug!(
+                            "Committed {} migration(s), {} applied so far",
+                            in_batch,
+                            report.applied.len()
+                        );
+
+                        batch = match conn.transaction().await {
+                            Ok(transaction) => Some(transaction),
+                            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+                        };
+                        committed = results.len();
+                        in_batch = 0;
+                    }
+                }
+            }
+            Err(e) => {
+                if let Some(batch) = batch {
+                    let _ = batch.rollback().await;
+                    for result in results.iter_mut().skip(committed) {
+                        if result.status == FileStatus::Applied {
+                            result.status = FileStatus::RolledBack;
+                        }
+                    }
+                }
+                error!("Error while executing migration {}", name);
+                results.push(FileResult {
+                    name: name.clone(),
+                    status: FileStatus::Failed,
+                    duration_ms: Some(started.elapsed().as_millis()),
+                    error: Some(e.to_string()),
+                });
+                return Err(AppError::MigrationFailed { file: name, source: Box::new(e) });
+            }
+        };
+    }
+
+    if let Some(batch) = batch {
+        if let Err(e) = batch.commit().await {
+            return Err(AppError::DatabaseError(e.to_string()));
+        }
+        if config.commit_every > 0 {
+            info!(
+                "Committed {} migration(s) in transactions of up to {}",
+                batched, config.commit_every
+            );
+        } else {
+            info!("Committed {} migration(s) in a single transaction", batched);
+        }
+    }
+
+    if let Some(path) = &config.output_sql {
+        // the run left the table as it is, the script creates or upgrades it first
+        let script = format!(
+            "-- pending migrations compiled by trso-migrator, run them as one script\nBEGIN;\n{};\n{}\nCOMMIT;\n",
+            table_upgrades(conn, config).await?.join(";\n"),
+            script
+        );
+        if let Err(e) = std::fs::write(path, script) {
+            return Err(AppError::IOError(format!(
+                "cannot write {}: {}",
+                path.display(),
+                e
+            )));
+        }
+        info!(
+            "Wrote {} pending migration(s) to {}",
+            report.applied.len(),
+            path.display()
+        );
+    }
+
+    // the files themselves are only listed with --verbose
+    if !report.skipped.is_empty() {
+        info!("Skipped {} already applied migration(s).", report.skipped.len());
+    }
+
+    if report.applied.is_empty() {
+        info!("No pending migrations; database is up to date.");
+    } else if config.dry_run {
+        info!("{} pending migration(s).", report.applied.len());
+    } else {
+        info!("Applied {} migration(s).", report.applied.len());
+    }
+
+    if held_back > 0 {
+        info!("{} more migration(s) are still pending.", held_back);
+    }
+
+    Ok(report)
+}
+
+/// Rolls back the last applied migration and returns how many were rolled back,
+/// in dry run mode the rollback script is only printed.
+pub async fn rollback_database(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+) -> Result<usize, AppError> {
+    with_lock(conn, config, run_rollback(conn, config, source)).await
+}
+
+async fn run_rollback(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+) -> Result<usize, AppError> {
+    create_migrations_table(conn, config).await?;
+
+    let (id, name) = match last_applied(conn, config).await? {
+        Some(last) => last,
+        None => {
+            info!("nothing to roll back, no migrations are applied");
+            return Ok(0);
+        }
+    };
+
+    let (down_name, migration_content) = read_down_file(config, source, &name)?;
+
+    if config.dry_run {
+        println!("Pending rollback {} of {}:", down_name, name);
+        println!("{}", migration_content);
+        return Ok(1);
+    }
+
+    let transaction = match conn.transaction().await {
+        Ok(transaction) => transaction,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+    if let Err(e) = execute_sql(&transaction, &down_name, &migration_content, config).await {
+        let _ = transaction.rollback().await;
+        error!("Error while rolling back migration {}", name);
+        return Err(AppError::MigrationFailed { file: down_name, source: Box::new(e) });
+    }
+
+    // the down script is only kept together with the deleted record
+    let sql = format!("DELETE FROM {} WHERE rowid = ?1", config.table_name);
+    if let Err(e) = transaction.execute(&sql, [id]).await {
+        let _ = transaction.rollback().await;
+        return Err(AppError::DatabaseError(format!(
+            "cannot delete the record of {}, its rollback was undone: {}",
+            name, e
+        )));
+    }
+    if let Err(e) = transaction.commit().await {
+        return Err(AppError::DatabaseError(e.to_string()));
+    }
+
+    let message = format!("Migration rolled back for file {}", name);
+    info!("{}", color::log(config, Color::Green, message));
+
+    Ok(1)
+}
+
+/// Row id and file name of the most recently applied migration, the rowid is
+/// `id` in tables created by the migrator.
+async fn last_applied(
+    conn: &Connection,
+    config: &Config,
+) -> Result<Option<(i64, String)>, AppError> {
+    let table = config.table_name.as_str();
+    let sql = format!(
+        "SELECT rowid, {} FROM {} ORDER BY rowid DESC LIMIT 1",
+        config.file_name_column, table
+    );
+    let mut rows = match conn.query(&sql, ()).await {
+        Ok(rows) => rows,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let row = match rows.next().await {
+        Ok(Some(row)) => row,
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let id = match row.get::<i64>(0) {
+        Ok(id) => id,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+    let name = match text_column(&row, 1, table)? {
+        Some(name) => name,
+        None => {
+            return Err(AppError::DatabaseError(format!(
+                "{} contains a row without a file_name",
+                table
+            )))
+        }
+    };
+
+    // the open statement keeps the table locked, which would break DROP TABLE
+    drop(rows);
+
+    Ok(Some((id, name)))
+}
+
+/// Reads the `.down.sql` file paired with an applied migration, returns its
+/// name and content.
+fn read_down_file(
+    config: &Config,
+    source: &dyn MigrationSource,
+    name: &str,
+) -> Result<(String, String), AppError> {
+    let down_name = down_file_name(name);
+    let down_file = match find_migration_file(config, source, &down_name)? {
+        Some(entry) => entry,
+        None => {
+            return Err(AppError::IOError(format!(
+                "cannot roll back {}, rollback file {} does not exist",
+                name, down_name
+            )))
+        }
+    };
+
+    let content = source.content(&down_file)?;
+
+    Ok((down_name, content))
+}
+
+/// Rolls back the last applied migration and applies it again in a single
+/// transaction, a failure leaves it applied as before. Returns how many
+/// migrations were redone, in dry run mode both scripts are only printed.
+pub async fn redo_database(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+) -> Result<usize, AppError> {
+    with_lock(conn, config, run_redo(conn, config, source)).await
+}
+
+async fn run_redo(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+) -> Result<usize, AppError> {
+    create_migrations_table(conn, config).await?;
+
+    let (id, name) = match last_applied(conn, config).await? {
+        Some(last) => last,
+        None => {
+            info!("nothing to redo, no migrations are applied");
+            return Ok(0);
+        }
+    };
+
+    let (down_name, down_content) = read_down_file(config, source, &name)?;
+    let up_file = match find_migration(config, source, &name)? {
+        Some(entry) => entry,
+        None => {
+            return Err(AppError::IOError(format!(
+                "cannot redo {}, the migration file does not exist",
+                name
+            )))
+        }
+    };
+    let up_content = source.content(&up_file)?;
+
+    if config.dry_run {
+        println!("Pending rollback {} of {}:", down_name, name);
+        println!("{}", down_content);
+        println!("Pending migration {}:", name);
+        println!("{}", up_content);
+        return Ok(1);
+    }
+
+    let transaction = match conn.transaction().await {
+        Ok(transaction) => transaction,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let result = async {
+        execute_sql(&transaction, &down_name, &down_content, config).await?;
+
+        let sql = format!("DELETE FROM {} WHERE rowid = ?1", config.table_name);
+        if let Err(e) = transaction.execute(&sql, [id]).await {
+            return Err(AppError::DatabaseError(e.to_string()));
+        }
+
+        apply_migration(&transaction, &name, &up_content, config).await
+    }
+    .await;
+
+    match result {
+        Ok(duration) => {
+            if let Err(e) = transaction.commit().await {
+                return Err(AppError::DatabaseError(e.to_string()));
+            }
+            let message = format!("Migration redone for file {} in {:.2?}", name, duration);
+            info!("{}", color::log(config, Color::Green, message));
+        }
+        Err(e) => {
+            let _ = transaction.rollback().await;
+            error!("Error while redoing migration {}", name);
+            return Err(AppError::MigrationFailed { file: name, source: Box::new(e) });
+        }
+    }
+
+    Ok(1)
+}
+
+/// Deletes the record of an applied migration and applies it again in a
+/// single transaction, for files that only partially ran. The rollback script
+/// isn't used. Returns how many migrations were reapplied, in dry run mode the
+/// file is only printed.
+pub async fn reapply_database(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    name: &str,
+) -> Result<usize, AppError> {
+    let name = &recorded_name(name);
+    with_lock(conn, config, run_reapply(conn, config, source, name)).await
+}
+
+async fn run_reapply(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    name: &str,
+) -> Result<usize, AppError> {
+    create_migrations_table(conn, config).await?;
+
+    let in_database = load_applied_migrations(conn, config).await?;
+    if !in_database.contains_key(name) {
+        return Err(AppError::DatabaseError(format!(
+            "cannot reapply {}, the migration is not applied",
+            name
+        )));
+    }
+
+    let file = match find_migration(config, source, name)? {
+        Some(entry) => entry,
+        None => {
+            return Err(AppError::IOError(format!(
+                "cannot reapply {}, the migration file does not exist",
+                name
+            )))
+        }
+    };
+    let content = source.content(&file)?;
+
+    if config.dry_run {
+        println!("Pending migration {}:", name);
+        println!("{}", content);
+        return Ok(1);
+    }
+
+    let transaction = match conn.transaction().await {
+        Ok(transaction) => transaction,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let result = async {
+        let sql = format!(
+            "DELETE FROM {} WHERE {} = ?1",
+            config.table_name, config.file_name_column
+        );
+        if let Err(e) = transaction.execute(&sql, [name]).await {
+            return Err(AppError::DatabaseError(e.to_string()));
+        }
+
+        apply_migration(&transaction, name, &content, config).await
+    }
+    .await;
+
+    match result {
+        Ok(duration) => {
+            if let Err(e) = transaction.commit().await {
+                return Err(AppError::DatabaseError(e.to_string()));
+            }
+            let message = format!("Migration reapplied for file {} in {:.2?}", name, duration);
+            info!("{}", color::log(config, Color::Green, message));
+        }
+        Err(e) => {
+            let _ = transaction.rollback().await;
+            error!("Error while reapplying migration {}", name);
+            return Err(AppError::MigrationFailed { file: name.to_string(), source: Box::new(e) });
+        }
+    }
+
+    Ok(1)
+}
+
+/// Drops every table, view and trigger of the database, the migrations table
+/// included, so a following run applies every migration again. Returns how
+/// many objects were dropped, in dry run mode they are only listed.
+pub async fn reset_database(conn: &Connection, config: &Config) -> Result<usize, AppError> {
+    with_lock(conn, config, run_reset(conn, config)).await
+}
+
+async fn run_reset(conn: &Connection, config: &Config) -> Result<usize, AppError> {
+    // the lock table is in use by this run, indexes go with their tables
+    let sql = "SELECT type, name FROM sqlite_master WHERE type IN ('table', 'view', 'trigger') AND name NOT LIKE 'sqlite_%' AND name != 'migration_lock' ORDER BY rowid DESC";
+    let mut rows = match conn.query(sql, ()).await {
+        Ok(rows) => rows,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let mut objects = Vec::new();
+    loop {
+        let row = match rows.next().await {
+            Ok(Some(row)) => row,
+            Ok(None) => break,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+
+        match (row.get::<String>(0), row.get::<String>(1)) {
+            (Ok(kind), Ok(name)) => objects.push((kind, name)),
+            (Err(e), _) | (_, Err(e)) => return Err(AppError::DatabaseError(e.to_string())),
+        }
+    }
+
+    if config.dry_run {
+        for (kind, name) in &objects {
+            println!("Pending drop of {} {}", kind, name);
+        }
+        return Ok(objects.len());
+    }
+
+    // tables referencing each other can only be dropped without the checks,
+    // they are switched back on afterwards if they were on
+    let foreign_keys = match conn.query("PRAGMA foreign_keys", ()).await {
+        Ok(mut rows) => match rows.next().await {
+            Ok(Some(row)) => row.get::<i64>(0).unwrap_or(0),
+            _ => 0,
+        },
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+    if let Err(e) = conn.execute("PRAGMA foreign_keys = OFF", ()).await {
+        return Err(AppError::DatabaseError(e.to_string()));
+    }
+
+    let mut result = Ok(objects.len());
+    for (kind, name) in &objects {
+        let sql = format!(
+            "DROP {} IF EXISTS \"{}\"",
+            kind.to_uppercase(),
+            name.replace('"', "\"\"")
+        );
+        if let Err(e) = conn.execute(&sql, ()).await {
+            result = Err(AppError::DatabaseError(format!(
+                "cannot drop {} {}: {}",
+                kind, name, e
+            )));
+            break;
+        }
+        debug!("Dropped {} {}", kind, name);
+    }
+
+    if foreign_keys == 1 {
+        if let Err(e) = conn.execute("PRAGMA foreign_keys = ON", ()).await {
+            return Err(AppError::DatabaseError(e.to_string()));
+        }
+    }
+
+    result
+}
+
+/// Records every migration file up to and including `target` as applied
+/// without executing it, for databases whose schema already exists. Unless
+/// `force` is set the migrations table has to be empty. Returns how many files
+/// were recorded, in dry run mode only the files that would be are listed.
+pub async fn baseline_database(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    target: &str,
+    force: bool,
+) -> Result<usize, AppError> {
+    with_lock(conn, config, run_baseline(conn, config, source, target, force)).await
+}
+
+async fn run_baseline(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    target: &str,
+    force: bool,
+) -> Result<usize, AppError> {
+    check_naming(config, source)?;
+    check_versions(config, source)?;
+    let list_files = list_migrations(config, source)?;
+    let target = recorded_name(target);
+    let position = match list_files.iter().position(|entry| entry.name == target) {
+        Some(position) => position,
+        None => {
+            return Err(AppError::IOError(format!(
+                "cannot baseline, migration {} does not exist",
+                target
+            )))
+        }
+    };
+
+    create_migrations_table(conn, config).await?;
+    let in_database = load_applied_migrations(conn, config).await?;
+    if !in_database.is_empty() && !force {
+        return Err(AppError::DatabaseError(format!(
+            "cannot baseline, {} already has {} migration(s) recorded, use --force to baseline anyway",
+            config.table_name,
+            in_database.len()
+        )));
+    }
+
+    let pending: Vec<MigrationEntry> = list_files
+        .into_iter()
+        .take(position + 1)
+        .filter(|entry| !in_database.contains_key(&entry.name))
+        .collect();
+
+    if config.dry_run {
+        for entry in &pending {
+            println!("Pending baseline of {}", entry.name);
+        }
+        return Ok(pending.len());
+    }
+
+    let transaction = match conn.transaction().await {
+        Ok(transaction) => transaction,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let mut recorded = 0;
+    for entry in pending {
+        let content = match source.content(&entry) {
+            Ok(content) => content,
+            Err(e) => {
+                let _ = transaction.rollback().await;
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = record_migration(&transaction, config, &entry.name, &content, None).await {
+            let _ = transaction.rollback().await;
+            return Err(record_error(e, &entry.name, &config.table_name));
+        }
+
+        let message = format!("Recorded migration {} as applied", entry.name);
+        info!("{}", color::log(config, Color::Green, message));
+        recorded += 1;
+    }
+
+    if let Err(e) = transaction.commit().await {
+        return Err(AppError::DatabaseError(e.to_string()));
+    }
+
+    Ok(recorded)
+}
+
+/// Records a single migration file as applied without executing it, e.g. one
+/// that was run by hand. Returns 0 when it is already recorded, in dry run mode
+/// nothing is recorded.
+pub async fn mark_database(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    name: &str,
+) -> Result<usize, AppError> {
+    with_lock(conn, config, run_mark(conn, config, source, name, true)).await
+}
+
+/// Deletes the record of a single applied migration without rolling it back,
+/// so the next run applies it again. Returns 0 when it isn't recorded, in dry
+/// run mode nothing is deleted.
+pub async fn unmark_database(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    name: &str,
+) -> Result<usize, AppError> {
+    with_lock(conn, config, run_mark(conn, config, source, name, false)).await
+}
+
+async fn run_mark(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    name: &str,
+    applied: bool,
+) -> Result<usize, AppError> {
+    let name = recorded_name(name);
+    let entry = match find_migration(config, source, &name)? {
+        Some(entry) => entry,
+        None => {
+            return Err(AppError::IOError(format!(
+                "migration {} does not exist",
+                name
+            )))
+        }
+    };
+
+    create_migrations_table(conn, config).await?;
+    let in_database = load_applied_migrations(conn, config).await?;
+    if in_database.contains_key(&name) == applied {
+        let state = if applied { "already" } else { "not" };
+        info!("Migration {} is {} recorded as applied", name, state);
+        return Ok(0);
+    }
+
+    if config.dry_run {
+        let action = if applied { "mark" } else { "unmark" };
+        println!("Pending {} of {}", action, name);
+        return Ok(1);
+    }
+
+    if applied {
+        let content = source.content(&entry)?;
+        if let Err(e) = record_migration(conn, config, &name, &content, None).await {
+            return Err(record_error(e, &name, &config.table_name));
+        }
+
+        let message = format!("Recorded migration {} as applied", name);
+        info!("{}", color::log(config, Color::Green, message));
+    } else {
+        let sql = format!(
+            "DELETE FROM {} WHERE {} = ?1",
+            config.table_name, config.file_name_column
+        );
+        if let Err(e) = conn.execute(&sql, [name.as_str()]).await {
+            return Err(AppError::DatabaseError(e.to_string()));
+        }
+
+        let message = format!("Removed the record of migration {}", name);
+        info!("{}", color::log(config, Color::Yellow, message));
+    }
+
+    Ok(1)
+}
+
+/// How the migrations table drifted from the migration files, found by
+/// [`find_discrepancies`]. Every list is sorted like the files.
+#[derive(Debug, Default)]
+pub struct Discrepancies {
+    /// Recorded migrations whose file no longer exists.
+    pub missing_files: Vec<String>,
+
+    /// Pending migrations sorting before the latest applied one.
+    pub out_of_order: Vec<String>,
+
+    /// Recorded migrations without a checksum, e.g. applied by an old version.
+    pub missing_checksums: Vec<String>,
+}
+
+impl Discrepancies {
+    pub fn is_empty(&self) -> bool {
+        self.missing_files.is_empty()
+            && self.out_of_order.is_empty()
+            && self.missing_checksums.is_empty()
+    }
+}
+
+/// Compares the migrations table with the migration files, read-only. Files
+/// tagged for another environment are never out of order.
+pub async fn find_discrepancies(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+) -> Result<Discrepancies, AppError> {
+    validate_table_name(&config.table_name)?;
+    let list_files = list_migrations(config, source)?;
+
+    let mut discrepancies = Discrepancies::default();
+    if table_columns(conn, &config.table_name).await?.is_empty() {
+        return Ok(discrepancies);
+    }
+    let in_database = load_applied_migrations(conn, config).await?;
+
+    let mut recorded: Vec<&String> = in_database.keys().collect();
+    recorded.sort_by(|a, b| compare_names(a, b));
+    for name in recorded {
+        if !list_files.iter().any(|entry| entry.name == *name) {
+            discrepancies.missing_files.push(name.clone());
+        } else if in_database[name].checksum.is_none() && !is_adopted(config) {
+            discrepancies.missing_checksums.push(name.clone());
+        }
+    }
+
+    if let Some(latest) = in_database.keys().max_by(|a, b| compare_names(a, b)) {
+        for entry in &list_files {
+            let name = entry.name.as_str();
+            if in_database.contains_key(name) || compare_names(name, latest) != Ordering::Less {
+                continue;
+            }
+            match environment_tag(&source.content(entry)?) {
+                Some(tag) if config.environment.as_deref() != Some(tag) => (),
+                _ => discrepancies.out_of_order.push(entry.name.clone()),
+            }
+        }
+    }
+
+    Ok(discrepancies)
+}
+
+/// Writes the checksum of every recorded migration that has none from its
+/// file and, with `remove_missing`, deletes the records of migrations whose
+/// file no longer exists. No migration SQL is run, pending files stay
+/// pending. Returns how many rows were changed, in dry run mode how many
+/// would be.
+pub async fn repair_database(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    remove_missing: bool,
+) -> Result<usize, AppError> {
+    with_lock(conn, config, run_repair(conn, config, source, remove_missing)).await
+}
+
+async fn run_repair(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+    remove_missing: bool,
+) -> Result<usize, AppError> {
+    let discrepancies = find_discrepancies(conn, config, source).await?;
+    let table = config.table_name.as_str();
+
+    if config.dry_run {
+        for name in &discrepancies.missing_checksums {
+            println!("Pending checksum of {}", name);
+        }
+        if remove_missing {
+            for name in &discrepancies.missing_files {
+                println!("Pending removal of the record of {}", name);
+            }
+        }
+        let removed = if remove_missing { discrepancies.missing_files.len() } else { 0 };
+        return Ok(discrepancies.missing_checksums.len() + removed);
+    }
+
+    // an old table may not have the checksum column yet
+    create_migrations_table(conn, config).await?;
+
+    let mut changed = 0;
+    for name in &discrepancies.missing_checksums {
+        let entry = match find_migration(config, source, name)? {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let sql = format!(
+            "UPDATE {} SET checksum = ?1 WHERE {} = ?2 AND checksum IS NULL",
+            table, config.file_name_column
+        );
+        let params = libsql::params![checksum(&source.content(&entry)?), name.as_str()];
+        if let Err(e) = conn.execute(&sql, params).await {
+            return Err(AppError::DatabaseError(e.to_string()));
+        }
+
+        info!("Recorded the checksum of migration {}", name);
+        changed += 1;
+    }
+
+    if remove_missing {
+        for name in &discrepancies.missing_files {
+            let sql = format!("DELETE FROM {} WHERE {} = ?1", table, config.file_name_column);
+            if let Err(e) = conn.execute(&sql, [name.as_str()]).await {
+                return Err(AppError::DatabaseError(e.to_string()));
+            }
+
+            let message = format!("Removed the record of missing migration {}", name);
+            info!("{}", color::log(config, Color::Yellow, message));
+            changed += 1;
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Runs every migration against a throwaway in-memory database to check that
+/// it executes, the configured database is never opened. Each file runs in its
+/// own transaction on top of the files before it, failed ones are rolled back
+/// and the run goes on so all of them are reported. Returns how many files
+/// were checked.
+pub async fn validate_migrations(
+    config: &Config,
+    source: &dyn MigrationSource,
+) -> Result<usize, AppError> {
+    check_naming(config, source)?;
+    check_versions(config, source)?;
+    let list_files = list_migrations(config, source)?;
+
+    let db = match Builder::new_local(":memory:").build().await {
+        Ok(db) => db,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+    let conn = match db.connect() {
+        Ok(conn) => conn,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let mut invalid = Vec::new();
+    for entry in &list_files {
+        let content = source.content(entry)?;
+
+        if without_transaction(&content) {
+            match execute_sql(&conn, &entry.name, &content, config).await {
+                Ok(_) => info!("Migration {} is valid", entry.name),
+                Err(e) => {
+                    error!("Migration {} is invalid: {}", entry.name, e);
+                    invalid.push(entry.name.as_str());
+                }
+            }
+            continue;
+        }
+
+        let transaction = match conn.transaction().await {
+            Ok(transaction) => transaction,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+        match execute_sql(&transaction, &entry.name, &content, config).await {
+            Ok(_) => {
+                if let Err(e) = transaction.commit().await {
+                    return Err(AppError::DatabaseError(e.to_string()));
+                }
+                info!("Migration {} is valid", entry.name);
+            }
+            Err(e) => {
+                let _ = transaction.rollback().await;
+                error!("Migration {} is invalid: {}", entry.name, e);
+                invalid.push(entry.name.as_str());
+            }
+        }
+    }
+
+    if !invalid.is_empty() {
+        return Err(AppError::DatabaseError(format!(
+            "{} of {} migration(s) failed: {}",
+            invalid.len(),
+            list_files.len(),
+            invalid.join(", ")
+        )));
+    }
+
+    Ok(list_files.len())
+}
+
+/// Prints every migration file with its applied/pending state, read-only.
+pub async fn print_status(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+) -> Result<(), AppError> {
+    validate_table_name(&config.table_name)?;
+    let migrations = list_migrations(config, source)?;
+
+    // status is read-only, a missing table just means nothing is applied yet
+    let in_database = if table_columns(conn, &config.table_name).await?.is_empty() {
+        HashMap::new()
+    } else {
+        load_applied_migrations(conn, config).await?
+    };
+
+    let width = migrations
+        .iter()
+        .map(|entry| entry.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("FILE NAME".len());
+
+    println!("{:<width$}  {:<7}  APPLIED AT", "FILE NAME", "STATUS");
+    for entry in migrations {
+        let name = entry.name;
+        match in_database.get(&name) {
+            // padded before coloring, the escape codes would count as width
+            Some(applied) => println!(
+                "{:<width$}  {}  {}",
+                name,
+                color::out(config, Color::Green, format!("{:<7}", "applied")),
+                applied.applied_at.as_deref().unwrap_or("-")
+            ),
+            None => println!(
+                "{:<width$}  {}  -",
+                name,
+                color::out(config, Color::Yellow, format!("{:<7}", "pending"))
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// The migrations not applied yet in file order, files tagged for another
+/// environment left out. Read-only like [`print_status`], the `--only`,
+/// `--since`, `--target` and `--tag` selections aren't applied.
+pub async fn pending_migrations(
+    conn: &Connection,
+    config: &Config,
+    source: &dyn MigrationSource,
+) -> Result<Vec<String>, AppError> {
+    validate_table_name(&config.table_name)?;
+    let migrations = list_migrations(config, source)?;
+
+    let in_database = if table_columns(conn, &config.table_name).await?.is_empty() {
+        HashMap::new()
+    } else {
+        load_applied_migrations(conn, config).await?
+    };
+
+    let mut pending = Vec::new();
+    for entry in migrations {
+        if in_database.contains_key(&entry.name) {
+            continue;
+        }
+        match environment_tag(&source.content(&entry)?) {
+            Some(tag) if config.environment.as_deref() != Some(tag) => (),
+            _ => pending.push(entry.name),
+        }
+    }
+
+    Ok(pending)
+}
+
+/// A row of the migrations table in `list-applied` JSON output.
+#[derive(Serialize)]
+struct AppliedRecord {
+    id: i64,
+
+    file_name: String,
+
+    applied_at: Option<String>,
+}
+
+/// Prints the migrations table in the order the migrations were applied,
+/// without reading the migration files. `json` prints a JSON array instead.
+pub async fn print_applied(conn: &Connection, config: &Config, json: bool) -> Result<(), AppError> {
+    validate_table_name(&config.table_name)?;
+    validate_columns(config)?;
+    let table = &config.table_name;
+
+    // a missing table means nothing is applied yet and is left uncreated
+    let columns = table_columns(conn, table).await?;
+    check_table_schema(config, &columns)?;
+    let mut records = Vec::new();
+    if !columns.is_empty() {
+        let applied_at = if columns.contains(&config.applied_at_column) {
+            config.applied_at_column.as_str()
+        } else {
+            "NULL"
+        };
+        let sql = format!(
+            "SELECT rowid, {}, {} FROM {} ORDER BY rowid",
+            config.file_name_column, applied_at, table
+        );
+        let mut rows = match conn.query(&sql, ()).await {
+            Ok(rows) => rows,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+
+        loop {
+            let row = match rows.next().await {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+            };
+
+            let id = match row.get::<i64>(0) {
+                Ok(id) => id,
+                Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+            };
+            let file_name = match text_column(&row, 1, table)? {
+                Some(name) => name,
+                None => {
+                    return Err(AppError::DatabaseError(format!(
+                        "{} contains a row without a file_name",
+                        table
+                    )))
+                }
+            };
+            let applied_at = text_column(&row, 2, table)?;
+            records.push(AppliedRecord { id, file_name, applied_at });
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string(&records).unwrap());
+        return Ok(());
+    }
+
+    let id_width = records
+        .iter()
+        .map(|record| record.id.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("ID".len());
+    let width = records
+        .iter()
+        .map(|record| record.file_name.len())
+        .max()
+        .unwrap_or(0)
+        .max("FILE NAME".len());
+
+    println!("{:>id_width$}  {:<width$}  APPLIED AT", "ID", "FILE NAME");
+    for record in records {
+        println!(
+            "{:>id_width$}  {:<width$}  {}",
+            record.id,
+            record.file_name,
+            record.applied_at.as_deref().unwrap_or("-")
+        );
+    }
+
+    Ok(())
+}
+
+/// A file of the `checksums` JSON manifest.
+#[derive(Serialize)]
+struct FileChecksum {
+    file_name: String,
+
+    checksum: String,
+}
+
+/// A manifest of every file of `source`, rollback files and hooks included,
+/// sorted by name with the SHA-256 the migrations table records for it. One
+/// `checksum  file_name` line per file, `json` gives a JSON array instead.
+/// Gzipped files are hashed by their decompressed content.
+pub fn checksum_manifest(source: &dyn MigrationSource, json: bool) -> Result<String, AppError> {
+    let mut entries = source.list()?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut files = Vec::new();
+    for entry in &entries {
+        files.push(FileChecksum {
+            file_name: entry.name.clone(),
+            checksum: checksum(&source.content(entry)?),
+        });
+    }
+
+    if json {
+        return Ok(format!("{}\n", serde_json::to_string(&files).unwrap()));
+    }
+
+    let mut manifest = String::new();
+    for file in files {
+        manifest.push_str(&format!("{}  {}\n", file.checksum, file.file_name));
+    }
+    Ok(manifest)
+}
+
+/// The schema of the database as SQL, one statement per object sorted by
+/// type and name, so dumps of two databases can be diffed. Read-only and
+/// independent of the migrations table.
+pub async fn dump_schema(conn: &Connection) -> Result<String, AppError> {
+    let sql = "SELECT sql FROM sqlite_master WHERE sql IS NOT NULL ORDER BY type, name";
+    let mut rows = match conn.query(sql, ()).await {
+        Ok(rows) => rows,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+
+    let mut statements = Vec::new();
+    loop {
+        let row = match rows.next().await {
+            Ok(Some(row)) => row,
+            Ok(None) => break,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+
+        if let Some(sql) = text_column(&row, 0, "sqlite_master")? {
+            statements.push(normalize_statement(&sql));
+        }
+    }
+
+    let mut schema = statements.join("\n\n");
+    if !schema.is_empty() {
+        schema.push('\n');
+    }
+    Ok(schema)
+}
+
+/// `sql` without trailing whitespace on its lines and ending in a semicolon,
+/// SQLite keeps the statement text exactly as it was written.
+fn normalize_statement(sql: &str) -> String {
+    let lines: Vec<&str> = sql.trim().lines().map(str::trim_end).collect();
+    let mut statement = lines.join("\n");
+    if !statement.ends_with(';') {
+        statement.push(';');
+    }
+    statement
+}
+
+/// Current UTC time formatted as `YYYYMMDDhhmmss` and `YYYY-MM-DD hh:mm:ss`.
+fn utc_now() -> (String, String) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let (hour, minute, second) = (rem / 3600, rem % 3600 / 60, rem % 60);
+
+    // civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        format!(
+            "{:04}{:02}{:02}{:02}{:02}{:02}",
+            year, month, day, hour, minute, second
+        ),
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year, month, day, hour, minute, second
+        ),
+    )
+}
+
+/// Creates `<timestamp>_<name>.sql`, or a `.up.sql`/`.down.sql` pair, in `path`
+/// and returns the created files. `path` is only created when `create_dir` is set.
+pub fn create_migration(
+    path: &str,
+    name: &str,
+    up_down: bool,
+    create_dir: bool,
+) -> Result<Vec<PathBuf>, AppError> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(AppError::IOError(format!(
+            "invalid migration name {:?}, use letters, digits, _ and -",
+            name
+        )));
+    }
+
+    let dir = Path::new(path);
+    if !dir.is_dir() {
+        if !create_dir {
+            return Err(AppError::IOError(format!(
+                "migrations folder {} does not exist, pass --create-dir to create it",
+                path
+            )));
+        }
+
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            return Err(AppError::IOError(e.to_string()));
+        }
+    }
+
+    let (prefix, created_at) = utc_now();
+    let files = if up_down {
+        vec![
+            (format!("{}_{}.up.sql", prefix, name), "Migration"),
+            (format!("{}_{}.down.sql", prefix, name), "Rollback of"),
+        ]
+    } else {
+        vec![(format!("{}_{}.sql", prefix, name), "Migration")]
+    };
+
+    let mut created = Vec::new();
+    for (file_name, title) in files {
+        let file_path = dir.join(file_name);
+        let mut file = match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&file_path)
+        {
+            Ok(file) => file,
+            Err(e) => {
+                return Err(AppError::IOError(format!(
+                    "cannot create {}: {}",
+                    file_path.display(),
+                    e
+                )))
+            }
+        };
+
+        let header = format!("-- {} {}\n-- Created at {} UTC\n\n", title, name, created_at);
+        if let Err(e) = file.write_all(header.as_bytes()) {
+            return Err(AppError::IOError(e.to_string()));
+        }
+
+        created.push(file_path);
+    }
+
+    Ok(created)
+}
+
+/// Content of the sample migration written by [`init_migrations`].
+const INITIAL_MIGRATION: &str = "\
+-- Migrations run in the order of their leading number, each one exactly once.
+-- Every file runs in its own transaction and is recorded in the migrations
+-- table, an applied file must not be changed afterwards.
+--
+-- Add an optional 0001_initial.down.sql with the statements that undo this
+-- file to roll it back with TRSO_DIRECTION=down.
+
+CREATE TABLE IF NOT EXISTS example (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+";
+
+/// Creates the migrations folder at `path` with a sample `0001_initial.sql`
+/// and returns the path of the sample. A folder that already has files is
+/// left alone.
+pub fn init_migrations(path: &str) -> Result<PathBuf, AppError> {
+    let dir = Path::new(path);
+    if dir.exists() {
+        let mut entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                return Err(AppError::IOError(format!(
+                    "cannot read the migrations folder {}: {}",
+                    path, e
+                )))
+            }
+        };
+        if entries.next().is_some() {
+            return Err(AppError::IOError(format!(
+                "migrations folder {} is not empty, nothing was created",
+                path
+            )));
+        }
+    } else if let Err(e) = std::fs::create_dir_all(dir) {
+        return Err(AppError::IOError(e.to_string()));
+    }
+
+    let file_path = dir.join("0001_initial.sql");
+    if let Err(e) = std::fs::write(&file_path, INITIAL_MIGRATION) {
+        return Err(AppError::IOError(format!(
+            "cannot create {}: {}",
+            file_path.display(),
+            e
+        )));
+    }
+
+    Ok(file_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_errors_are_transient() {
+        for message in [
+            "Hrana: `stream closed: `connection reset by peer``",
+            "the stream has expired due to inactivity",
+            "error sending request: Connection refused (os error 111)",
+            "Hrana: `api error: `status=503 Service Unavailable``",
+        ] {
+            assert!(is_transient(message), "{} should be transient", message);
+        }
+    }
+
+    #[test]
+    fn sql_errors_naming_a_stream_are_not_transient() {
+        for message in [
+            "SQLite failure: `no such table: event_stream`",
+            "SQLite failure: `UNIQUE constraint failed: streams.id`",
+            "SQLite failure: `near \"upstream\": syntax error`",
+        ] {
+            assert!(!is_transient(message), "{} should not be transient", message);
+        }
+    }
+}
@@ -1,165 +1,1336 @@
-use std::{collections::HashMap, env, io};
+use std::{
+    collections::HashMap,
+    env,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
-use libsql::{Builder, Connection};
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
+use serde::Deserialize;
+use trso_migrator::{
+    baseline_database, check_down_files, checksum_manifest, connect, create_migration, dump_schema,
+    find_discrepancies, init_migrations, mark_database, migrate_database, notify_webhook, paint,
+    pending_migrations, print_applied, print_status, reapply_database, redo_database,
+    repair_database, reset_database, rollback_database, sync_replica, unmark_database,
+    validate_migrations, AppError, Color, Config, Direction, DownPairing, ErrorReport,
+    MigrationSource, OutputFormat, RunSummary, Tracking,
+};
 
-#[derive(Debug)]
-struct Config {
-    url_or_path: String,
+#[cfg(feature = "embed")]
+use trso_migrator::EmbeddedSource;
+#[cfg(not(feature = "embed"))]
+use trso_migrator::{ArchiveSource, FsSource};
 
-    local: bool,
+// resolved by build.rs, see TRSO_EMBED_DIR
+#[cfg(feature = "embed")]
+static EMBEDDED_MIGRATIONS: include_dir::Dir<'static> =
+    include_dir::include_dir!("$TRSO_EMBEDDED_MIGRATIONS");
 
-    token: String,
+/// Command line arguments, every option overrides its environment variable.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    migrations_path: String,
+    /// Database DSN, e.g. libsql://db.turso.io?authToken=... or file:local.db, `-` reads it
+    /// from stdin [env: TRSO_DSN]
+    #[arg(long, global = true)]
+    dsn: Option<String>,
+
+    /// Read the DSN from the first line of stdin, same as --dsn -
+    #[arg(long, global = true, conflicts_with = "dsn")]
+    dsn_stdin: bool,
+
+    /// File path or the url of the remote database [env: TRSO_PATH_URL]
+    #[arg(long, global = true)]
+    path: Option<String>,
+
+    /// Auth token of the remote database [env: TRSO_TOKEN]
+    #[arg(long, global = true)]
+    token: Option<String>,
+
+    /// Use a local database file instead of a remote one [env: TRSO_LOCAL]
+    #[arg(long, global = true, num_args = 0..=1, default_missing_value = "true")]
+    local: Option<bool>,
+
+    /// Local file of an embedded replica to migrate and sync [env: TRSO_REPLICA_PATH]
+    #[arg(long, global = true)]
+    replica_path: Option<String>,
+
+    /// Folder where the migration files are located, can be repeated [env: TRSO_MIGRATIONS_PATH]
+    #[arg(long, global = true)]
+    migrations_path: Vec<String>,
+
+    /// Also log skipped migrations, twice to log the SQL of every file too
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Seconds to wait for a busy database or the migration lock of another run
+    /// [env: TRSO_LOCK_TIMEOUT_SECS]
+    #[arg(long, global = true, value_name = "SECS")]
+    lock_timeout: Option<u64>,
+
+    /// Table the applied migrations are recorded in, e.g. to keep apart from another
+    /// tool's table [env: TRSO_TABLE_NAME]
+    #[arg(long, global = true)]
+    table_name: Option<String>,
+
+    /// Stop after applying this migration file [env: TRSO_TARGET]
+    #[arg(long, global = true)]
+    target: Option<String>,
+
+    /// Apply only this migration file, skipping every other one
+    #[arg(long, global = true, value_name = "FILE_NAME", conflicts_with = "target")]
+    only: Option<String>,
+
+    /// Only apply migration files sorting after this one
+    #[arg(long, global = true, value_name = "FILE_NAME", conflicts_with = "only")]
+    since: Option<String>,
+
+    /// Apply at most this many pending migration files, the rest stays pending
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        conflicts_with = "only",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    count: Option<usize>,
+
+    /// Apply only the pending migration files tagged with `-- trso:tags NAME`
+    #[arg(long, global = true, value_name = "NAME", conflicts_with = "only")]
+    tag: Option<String>,
+
+    /// Record every migration up to and including this file as applied without
+    /// running them, the same as `baseline FILE_NAME --force`
+    #[arg(long, value_name = "FILE_NAME")]
+    assume_applied_up_to: Option<String>,
+
+    /// Delete the record of this applied migration file and apply it again
+    #[arg(long, value_name = "FILE_NAME")]
+    force_reapply: Option<String>,
+
+    /// Apply pending migrations again whenever a migration file changes, local databases only
+    #[arg(long)]
+    watch: bool,
+
+    /// Write the pending migrations and the rows recording them as one SQL script
+    /// to this file instead of applying them
+    #[arg(long, value_name = "FILE", conflicts_with = "watch")]
+    output_sql: Option<PathBuf>,
+
+    /// With TRSO_DSN_LIST, go on with the next database when one fails
+    #[arg(long, global = true)]
+    continue_on_error: bool,
+
+    /// Don't ask for confirmation before applying to a remote database, reapplying a
+    /// migration or resetting [env: TRSO_ASSUME_YES]
+    #[arg(long, global = true)]
+    yes: bool,
+
+    /// Fail before connecting when a migration has no .down.sql file, `up` checks only
+    /// .up.sql files and `all` every migration
+    #[arg(
+        long,
+        global = true,
+        value_name = "RULE",
+        value_parser = ["up", "all"],
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "up"
+    )]
+    fail_on_missing_down: Option<String>,
+
+    /// Print the resolved configuration with the token masked and exit
+    #[arg(long, global = true)]
+    print_config: bool,
+
+    /// Don't color the output, also disabled by a non-empty NO_COLOR
+    #[arg(long, global = true)]
+    no_color: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check that the database can be connected to, without reading any migration
+    Ping,
+    /// Print applied and pending migrations without changing anything
+    Status,
+    /// Print the recorded migrations in the order they were applied
+    ListApplied {
+        /// Print a JSON array instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Export the schema of the database as sorted SQL, e.g. to diff two environments
+    DumpSchema {
+        /// Write the schema to this file instead of stdout
+        #[arg(long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+    /// Print the SHA-256 of every migration file, e.g. as an audit manifest for a release
+    Checksums {
+        /// Print a JSON array instead of `checksum  file_name` lines
+        #[arg(long)]
+        json: bool,
+
+        /// Write the manifest to this file instead of stdout
+        #[arg(long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+    /// Create a new migration file prefixed with the current UTC timestamp
+    Create {
+        /// Name of the migration, e.g. create_users
+        name: String,
+
+        /// Create a .up.sql and .down.sql pair instead of a single file
+        #[arg(long)]
+        up_down: bool,
+
+        /// Create the migrations folder if it doesn't exist
+        #[arg(long)]
+        create_dir: bool,
+    },
+    /// Create the migrations folder with a sample migration to start from
+    Init,
+    /// Roll back the last applied migration and apply it again
+    Redo,
+    /// Drop every table of a local database and apply all migrations again
+    Reset {
+        /// Also reset a remote database or embedded replica
+        #[arg(long)]
+        force_remote: bool,
+    },
+    /// Run every migration against an in-memory database without touching the configured one
+    Validate,
+    /// Record every migration up to and including FILE_NAME as applied without running it
+    Baseline {
+        /// Last migration file that is already part of the schema
+        file_name: String,
+
+        /// Baseline even if migrations are already recorded
+        #[arg(long)]
+        force: bool,
+    },
+    /// Record a single migration file as applied without running it, e.g. one run by hand
+    Mark {
+        /// Migration file to record
+        file_name: String,
+    },
+    /// Delete the record of a single applied migration without rolling it back
+    Unmark {
+        /// Migration file whose record is deleted
+        file_name: String,
+    },
+    /// Report where the migrations table and the migration files disagree, never runs a
+    /// migration
+    Repair {
+        /// Record the missing checksums from the files
+        #[arg(long)]
+        fix: bool,
+
+        /// With --fix, also delete the records of migrations whose file is gone, after
+        /// confirmation
+        #[arg(long, requires = "fix")]
+        remove_missing: bool,
+    },
 }
 
-#[derive(Debug)]
-enum AppError {
-    DatabaseError(String),
-    IOError(String),
+/// Settings read from `trso.toml`, environment variables override them.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    url: Option<String>,
+
+    token: Option<String>,
+
+    local: Option<bool>,
+
+    migrations_path: Option<String>,
+
+    table_name: Option<String>,
+
+    /// Columns of an existing migrations table of another tool.
+    #[serde(default)]
+    columns: ColumnsConfig,
+
+    /// DSNs of several databases that all get the same migrations.
+    dsns: Option<Vec<String>>,
+}
+
+/// The `[columns]` table of `trso.toml`.
+#[derive(Default, Deserialize)]
+struct ColumnsConfig {
+    file_name: Option<String>,
+
+    applied_at: Option<String>,
+
+    version: Option<String>,
 }
 
-fn get_configs() -> Config {
-    let is_local = match env::var("TRSO_LOCAL") {
-        Ok(val) => val
-            .parse::<bool>()
-            .expect("TRSO_LOCAL should be either true or false"),
-        Err(_) => false,
+/// Parses `TRSO_CONFIG` or `trso.toml` in the current directory. A missing
+/// `trso.toml` is ignored, a malformed file is a hard error.
+fn load_config_file() -> Result<FileConfig, AppError> {
+    let (path, required) = match env::var("TRSO_CONFIG") {
+        Ok(path) => (PathBuf::from(path), true),
+        Err(_) => (PathBuf::from("trso.toml"), false),
     };
 
-    let url_or_path = env::var("TRSO_PATH_URL").expect("TRSO_PATH_URL has to be set");
-    let mut token = String::from("");
-    if !is_local {
-        token = env::var("TRSO_TOKEN").expect("if not TRSO_LOCAL=true, the TRSO_TOKEN must be set");
+    if !required && !path.exists() {
+        return Ok(FileConfig::default());
     }
 
-    // Get current directory in case the path is not set
-    let cwd = env::current_dir().unwrap().into_os_string().into_string().unwrap();
-    let cwd = format!("{}/migrations", cwd);
-    let migrations_path = env::var("TRSO_MIGRATIONS_PATH").unwrap_or(cwd);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            return Err(AppError::ConfigError(format!(
+                "cannot read config file {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+    match toml::from_str(&content) {
+        Ok(file) => Ok(file),
+        Err(e) => Err(AppError::ConfigError(format!(
+            "invalid config file {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+/// Logs to stderr filtered by `RUST_LOG`, or the migrator's own messages at the
+/// level of `-v`/`-vv` or `TRSO_LOG_LEVEL`, `info` by default. Info messages are
+/// printed as they are, other levels get a prefix. Errors are red and warnings
+/// yellow with `color` when stderr is a terminal.
+fn init_logging(verbose: u8, color: bool) {
+    let filter = env::var("RUST_LOG").unwrap_or_else(|_| {
+        let level = match verbose {
+            0 => env::var("TRSO_LOG_LEVEL").unwrap_or(String::from("info")),
+            1 => String::from("debug"),
+            _ => String::from("trace"),
+        };
+        format!("trso_migrator={}", level)
+    });
+
+    let color = color && std::io::stderr().is_terminal();
+    env_logger::Builder::new()
+        .parse_filters(&filter)
+        .format(move |buf, record| {
+            let line = match record.level() {
+                log::Level::Info => return writeln!(buf, "{}", record.args()),
+                level => format!("{}: {}", level.as_str().to_lowercase(), record.args()),
+            };
+
+            match record.level() {
+                log::Level::Error if color => writeln!(buf, "{}", paint(Color::Red, line)),
+                log::Level::Warn if color => writeln!(buf, "{}", paint(Color::Yellow, line)),
+                _ => writeln!(buf, "{}", line),
+            }
+        })
+        .init();
+}
 
-    Config {
-        local: is_local,
-        url_or_path,
-        token,
-        migrations_path
+/// Loads variables from `TRSO_ENV_FILE` or `.env` in the current directory,
+/// variables already set in the environment are kept. A missing `.env` is ignored.
+fn load_env_file() -> Result<(), AppError> {
+    let (path, required) = match env::var("TRSO_ENV_FILE") {
+        Ok(path) => (PathBuf::from(path), true),
+        Err(_) => (PathBuf::from(".env"), false),
+    };
+
+    if !required && !path.exists() {
+        return Ok(());
+    }
+
+    match dotenvy::from_path(&path) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(AppError::ConfigError(format!(
+            "cannot load env file {}: {}",
+            path.display(),
+            e
+        ))),
     }
 }
 
-async fn get_connection(config: &Config) -> Result<libsql::Connection, libsql::Error> {
-    let db = if config.local {
-        Builder::new_local(&config.url_or_path).build().await?
-    } else {
-        Builder::new_remote(config.url_or_path.clone(), config.token.clone())
-            .build()
+/// The `migrations` folders looked for when no path is set, in the order of
+/// `TRSO_MIGRATIONS_SEARCH`: `cwd` is the current directory and `exe` the
+/// directory of the binary.
+fn default_migrations_paths() -> Result<Vec<PathBuf>, AppError> {
+    let order = env::var("TRSO_MIGRATIONS_SEARCH").unwrap_or_else(|_| String::from("cwd,exe"));
+
+    let mut paths = Vec::new();
+    for base in order.split(',').map(str::trim).filter(|base| !base.is_empty()) {
+        let dir = match base {
+            "cwd" => env::current_dir().ok(),
+            "exe" => env::current_exe()
+                .ok()
+                .and_then(|exe| exe.parent().map(Path::to_path_buf)),
+            _ => {
+                return Err(AppError::ConfigError(format!(
+                    "TRSO_MIGRATIONS_SEARCH entries should be cwd or exe, got {}",
+                    base
+                )))
+            }
+        };
+
+        if let Some(dir) = dir {
+            paths.push(dir.join("migrations"));
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Whether the migrations path is set instead of searched for.
+#[cfg(not(feature = "embed"))]
+fn has_migrations_path(cli: &Cli, file: &FileConfig) -> bool {
+    !cli.migrations_path.is_empty()
+        || env::var_os("TRSO_MIGRATIONS_PATH").is_some()
+        || file.migrations_path.is_some()
+}
+
+fn get_migrations_path(cli: &Cli, file: &FileConfig) -> Result<String, AppError> {
+    // the first existing default folder, or the first one for create --create-dir
+    let searched = default_migrations_paths()?;
+    let default = searched
+        .iter()
+        .find(|path| path.is_dir())
+        .or(searched.first())
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !cli.migrations_path.is_empty() {
+        let paths = cli.migrations_path.iter().flat_map(env::split_paths);
+        return match env::join_paths(paths) {
+            Ok(joined) => Ok(joined.to_string_lossy().to_string()),
+            Err(e) => Err(AppError::ConfigError(format!(
+                "--migrations-path can't be joined into a path list: {}",
+                e
+            ))),
+        };
+    }
+
+    Ok(env::var("TRSO_MIGRATIONS_PATH")
+        .ok()
+        .or_else(|| file.migrations_path.clone())
+        .unwrap_or(default))
+}
+
+/// DSNs of `TRSO_DSN_LIST`, separated by commas or newlines, or the `dsns`
+/// array of the config file.
+fn dsn_list(file: &FileConfig) -> Vec<String> {
+    match env::var("TRSO_DSN_LIST") {
+        Ok(list) => list
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|dsn| !dsn.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(_) => file.dsns.clone().unwrap_or_default(),
+    }
+}
+
+/// Applies, or with `TRSO_DIRECTION=down` rolls back, the migrations on every
+/// database of a DSN list one after another and reports each of them. The
+/// first failure stops the run unless `--continue-on-error` is passed.
+async fn migrate_all(cli: &Cli, file: &FileConfig, dsns: &[String]) {
+    if cli.command.is_some() || cli.force_reapply.is_some() {
+        error!(
+            "A DSN list only applies or rolls back migrations, use a single TRSO_DSN for other commands"
+        );
+        std::process::exit(1);
+    }
+
+    // one Ctrl-C stops the run on the current database and skips the others
+    let cancel = Arc::new(AtomicBool::new(false));
+    cancel_on_ctrl_c(cancel.clone());
+
+    let started = Instant::now();
+    let mut dry_run = false;
+    let mut outcomes = Vec::new();
+    for (index, dsn) in dsns.iter().enumerate() {
+        if cancel.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let mut configs = match get_configs(cli, file, Some(dsn)) {
+            Ok(configs) => configs,
+            Err(e) => {
+                // the DSN can hold the token, only the part before its query is reported
+                let database = dsn.split('?').next().unwrap_or_default().to_string();
+                error!(
+                    "Invalid configuration of database {} ({}/{}) {}",
+                    database,
+                    index + 1,
+                    dsns.len(),
+                    e
+                );
+                outcomes.push((database, Err(e)));
+                if !cli.continue_on_error {
+                    break;
+                }
+                continue;
+            }
+        };
+        configs.cancel = cancel.clone();
+        dry_run = configs.dry_run;
+        info!(
+            "Migrating database {} ({}/{}) ...",
+            configs.url_or_path,
+            index + 1,
+            dsns.len()
+        );
+
+        let database_started = Instant::now();
+        let result = migrate_one(cli, file, &configs).await;
+        let summary = RunSummary::new(&configs, &result, database_started.elapsed());
+        notify_webhook(&configs, &summary).await;
+        if let Err(e) = &result {
+            error!("Error occured during the migration of {} {}", configs.url_or_path, e);
+        }
+        let failed = result.is_err();
+        outcomes.push((configs.url_or_path, result));
+        if failed && !cli.continue_on_error {
+            break;
+        }
+    }
+
+    let mut failures = 0;
+    let mut pending = 0;
+    for (database, result) in &outcomes {
+        match result {
+            Ok(count) => {
+                info!("{}: {} migration(s)", database, count);
+                pending += count;
+            }
+            Err(e) => {
+                error!("{}: failed, {}", database, e);
+                failures += 1;
+            }
+        }
+    }
+    info!(
+        "Migrated {} of {} database(s) in {:.2?}.",
+        outcomes.len() - failures,
+        dsns.len(),
+        started.elapsed()
+    );
+
+    if failures > 0 || outcomes.len() < dsns.len() {
+        std::process::exit(1);
+    }
+    if dry_run && pending > 0 {
+        std::process::exit(2);
+    }
+}
+
+/// One database of [`migrate_all`], returns how many migrations were applied
+/// or rolled back.
+async fn migrate_one(cli: &Cli, file: &FileConfig, configs: &Config) -> Result<usize, AppError> {
+    let (db, conn) = match connect(configs).await {
+        Ok(connected) => connected,
+        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+    };
+    if configs.replica_path.is_some() {
+        sync_before_run(&db, configs).await?;
+    }
+
+    let source = migration_source(cli, file, configs);
+    if matches!(configs.direction, Direction::Up)
+        && !confirm_remote(cli, &conn, configs, source.as_ref()).await
+    {
+        info!("Nothing was applied to {}.", configs.url_or_path);
+        return Ok(0);
+    }
+
+    let count = match configs.direction {
+        Direction::Up => migrate_database(&conn, configs, source.as_ref())
             .await?
+            .applied
+            .len(),
+        Direction::Down => rollback_database(&conn, configs, source.as_ref()).await?,
     };
 
-    let conn = db.connect()?;
+    if configs.replica_path.is_some() && !configs.dry_run {
+        sync_replica(&db, configs).await?;
+    }
+
+    Ok(count)
+}
+
+/// Pulls the primary's state into the embedded replica before a run, with
+/// `TRSO_SYNC_BEST_EFFORT=true` a failure only warns.
+async fn sync_before_run(db: &libsql::Database, configs: &Config) -> Result<(), AppError> {
+    match sync_replica(db, configs).await {
+        Err(e) if configs.sync_best_effort => {
+            warn!("{}, going on from the last synced state of the replica", e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Sets `cancel` on the first Ctrl-C so the running migration can finish, a
+/// second one aborts right away.
+fn cancel_on_ctrl_c(cancel: Arc<AtomicBool>) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("Stopping after the current migration, press Ctrl-C again to abort");
+            cancel.store(true, Ordering::SeqCst);
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+}
+
+/// Reads the DSN piped in by a secret manager, so the token shows up in
+/// neither the environment nor the arguments.
+fn read_dsn_stdin() -> Result<String, AppError> {
+    let mut dsn = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut dsn) {
+        return Err(AppError::ConfigError(format!("cannot read the DSN from stdin: {}", e)));
+    }
+
+    Ok(dsn.trim().to_string())
+}
+
+/// Whether `--dsn-stdin`, `--dsn -` or `TRSO_DSN=-` ask for the DSN on stdin.
+fn dsn_from_stdin(cli: &Cli) -> bool {
+    cli.dsn_stdin
+        || cli.dsn.as_deref() == Some("-")
+        || env::var("TRSO_DSN").is_ok_and(|dsn| dsn == "-")
+}
+
+/// Resolves the configuration, command line arguments take precedence over
+/// environment variables, then the config file and then the defaults. `dsn`
+/// is one of the databases of a DSN list or the one read from stdin and wins
+/// over every other DSN.
+fn get_configs(cli: &Cli, file: &FileConfig, dsn: Option<&str>) -> Result<Config, AppError> {
+    let mut vars: HashMap<String, String> = HashMap::new();
+    let from_file = [
+        ("TRSO_PATH_URL", file.url.clone()),
+        ("TRSO_LOCAL", file.local.map(|local| local.to_string())),
+        ("TRSO_TABLE_NAME", file.table_name.clone()),
+        ("TRSO_COLUMN_FILE_NAME", file.columns.file_name.clone()),
+        ("TRSO_COLUMN_APPLIED_AT", file.columns.applied_at.clone()),
+        ("TRSO_COLUMN_VERSION", file.columns.version.clone()),
+    ];
+    vars.extend(from_file.into_iter().filter_map(|(name, value)| Some((name.to_string(), value?))));
+    vars.extend(env::vars());
+
+    // a token file in the environment also wins over the token of the file
+    if let Some(token) = &file.token {
+        if !vars.contains_key("TRSO_TOKEN_FILE") {
+            vars.entry(String::from("TRSO_TOKEN")).or_insert(token.clone());
+        }
+    }
+
+    let from_cli = [
+        ("TRSO_DSN", cli.dsn.clone()),
+        ("TRSO_PATH_URL", cli.path.clone()),
+        ("TRSO_TOKEN", cli.token.clone()),
+        ("TRSO_LOCAL", cli.local.map(|local| local.to_string())),
+        ("TRSO_REPLICA_PATH", cli.replica_path.clone()),
+        ("TRSO_TARGET", cli.target.clone()),
+        ("TRSO_LOCK_TIMEOUT_SECS", cli.lock_timeout.map(|secs| secs.to_string())),
+        ("TRSO_TABLE_NAME", cli.table_name.clone()),
+    ];
+    vars.extend(from_cli.into_iter().filter_map(|(name, value)| Some((name.to_string(), value?))));
+
+    if let Some(dsn) = dsn {
+        vars.insert(String::from("TRSO_DSN"), dsn.to_string());
+    }
+
+    // validating runs against an in-memory database and checksums only read the
+    // files, no connection settings needed
+    if matches!(cli.command, Some(Command::Validate | Command::Checksums { .. })) {
+        for name in ["TRSO_DSN", "TRSO_TOKEN", "TRSO_TOKEN_FILE", "TRSO_REPLICA_PATH"] {
+            vars.remove(name);
+        }
+        vars.insert(String::from("TRSO_LOCAL"), String::from("true"));
+        vars.entry(String::from("TRSO_PATH_URL")).or_default();
+    }
+
+    let config = Config::from_map(&vars)?;
+    let migrations_path = get_migrations_path(cli, file)?;
 
-    return Ok(conn);
+    Ok(Config {
+        migrations_path,
+        only: cli.only.clone(),
+        since: cli.since.clone(),
+        count: cli.count,
+        tag: cli.tag.clone(),
+        // computing the pending files is all a run does then
+        dry_run: config.dry_run || cli.output_sql.is_some(),
+        output_sql: cli.output_sql.clone(),
+        color: color_enabled(cli),
+        ..config
+    })
 }
 
-async fn migrate_database(conn: &Connection, path: String) -> Result<(), AppError> {
-    let dir = match std::fs::read_dir(path.as_str()) {
-        Ok(dir) => dir,
-        Err(err) => return Err(AppError::IOError(err.to_string())),
+/// Colors are on unless `--no-color` is passed or `NO_COLOR` is set to
+/// anything, output that isn't a terminal is never colored.
+fn color_enabled(cli: &Cli) -> bool {
+    !cli.no_color && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+/// Asks on stderr whether to go on, anything but `y` or `yes` declines.
+fn confirm(question: &str) -> bool {
+    eprint!("{} [y/N] ", question);
+    let _ = std::io::stderr().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Logs the error of `report` after `context` and exits with 1. With
+/// `TRSO_OUTPUT=json` the report is printed to stderr as JSON instead, so
+/// scripts can tell a connection failure from a failing migration.
+fn fail(configs: &Config, context: &str, report: ErrorReport) -> ! {
+    if configs.output == OutputFormat::Json {
+        eprintln!("{}", serde_json::to_string(&report).unwrap());
+    } else {
+        error!("{} {}", context, report.message);
+    }
+    std::process::exit(1);
+}
+
+/// `--yes` or `TRSO_ASSUME_YES=true` answers every confirmation, e.g. in CI.
+fn assume_yes(cli: &Cli) -> bool {
+    cli.yes || env::var("TRSO_ASSUME_YES").is_ok_and(|value| value == "true")
+}
+
+/// The host of a database url, `libsql://db-org.turso.io` gives `db-org.turso.io`.
+fn database_host(url: &str) -> &str {
+    let host = url.split_once("://").map_or(url, |(_, rest)| rest);
+    host.split(['/', '?']).next().unwrap_or(host)
+}
+
+/// Before migrating a remote database from a terminal, lists the pending
+/// migrations and asks to type `yes` or the database name to go on. A run
+/// that isn't interactive, local or has nothing pending goes on right away.
+async fn confirm_remote(
+    cli: &Cli,
+    conn: &libsql::Connection,
+    configs: &Config,
+    source: &dyn MigrationSource,
+) -> bool {
+    if configs.local || configs.dry_run || assume_yes(cli) || !std::io::stdout().is_terminal() {
+        return true;
+    }
+
+    // the run itself reports a table or folder that can't be read
+    let pending = match pending_migrations(conn, configs, source).await {
+        Ok(pending) if pending.is_empty() => return true,
+        Ok(pending) => pending,
+        Err(_) => return true,
     };
 
-    let mut list_files = match dir
-        .map(|res| res.map(|e| e.path()))
-        .collect::<Result<Vec<_>, io::Error>>()
-    {
-        Ok(list) => list,
-        Err(e) => return Err(AppError::IOError(e.to_string())),
+    let host = database_host(&configs.url_or_path);
+    let name = host.split('.').next().unwrap_or(host);
+    eprintln!("About to apply {} migration(s) to {}:", pending.len(), host);
+    for file_name in &pending {
+        eprintln!("  {}", file_name);
+    }
+    eprint!("Type \"yes\" or the database name {} to continue: ", name);
+    let _ = std::io::stderr().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    let answer = answer.trim();
+    answer == "yes" || answer == name || answer == host
+}
+
+/// Migrations compiled in with the `embed` feature replace the folders on disk,
+/// so does an archive set in `TRSO_MIGRATIONS_ARCHIVE`.
+#[cfg_attr(feature = "embed", allow(unused_variables))]
+fn migration_source(cli: &Cli, file: &FileConfig, configs: &Config) -> Box<dyn MigrationSource> {
+    #[cfg(feature = "embed")]
+    return Box::new(EmbeddedSource::new(&EMBEDDED_MIGRATIONS, configs.recursive));
+
+    #[cfg(not(feature = "embed"))]
+    match env::var("TRSO_MIGRATIONS_ARCHIVE") {
+        Ok(archive) => match ArchiveSource::open(Path::new(&archive), configs.recursive) {
+            Ok(source) => Box::new(source),
+            Err(e) => {
+                error!("Error while reading the migrations {}", e);
+                std::process::exit(1);
+            }
+        },
+        Err(_) => {
+            let searched = match default_migrations_paths() {
+                Ok(searched) => searched,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            if !has_migrations_path(cli, file) && !searched.iter().any(|path| path.is_dir()) {
+                let searched: Vec<String> =
+                    searched.iter().map(|path| path.display().to_string()).collect();
+                error!(
+                    "No migrations folder found, tried {}. Set TRSO_MIGRATIONS_PATH or --migrations-path",
+                    searched.join(", ")
+                );
+                std::process::exit(1);
+            }
+
+            Box::new(FsSource::from_config(configs))
+        }
+    }
+}
+
+/// Modification time and size of every migration file, a change of either
+/// or a file coming or going starts a new run of `watch`.
+fn watched_files(source: &dyn MigrationSource) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+    let mut files: Vec<_> = match source.list() {
+        Ok(entries) => entries
+            .into_iter()
+            .map(|entry| {
+                let metadata = std::fs::metadata(&entry.location).ok();
+                let modified = metadata.as_ref().and_then(|metadata| metadata.modified().ok());
+                let len = metadata.map_or(0, |metadata| metadata.len());
+                (entry.location, modified, len)
+            })
+            .collect(),
+        Err(_) => Vec::new(),
     };
+    files.sort();
+    files
+}
 
-    list_files.sort();
+/// Applies the pending migrations and again after every change of the
+/// migration files until Ctrl-C. A failed run is logged and waits for the
+/// next change. The files are polled every `watch_poll_ms` instead of
+/// subscribing to file system events, the `notify` crate isn't among the
+/// dependencies and polling the few migration files costs next to nothing.
+/// A burst of saves is applied once it has been quiet for `watch_debounce_ms`.
+async fn watch(conn: &libsql::Connection, configs: &Config, source: &dyn MigrationSource) {
+    let poll = Duration::from_millis(configs.watch_poll_ms);
+    let debounce = Duration::from_millis(configs.watch_debounce_ms);
 
-    let result = conn.execute(
-        r#"
-            CREATE TABLE IF NOT EXISTS migrations 
-            (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                file_name TEXT);
-        "#,
-        (),
-    )
-    .await;
+    let mut seen = watched_files(source);
+    loop {
+        info!("Migration is starting ...");
+        let started = Instant::now();
+        match migrate_database(conn, configs, source).await {
+            Ok(_) => info!("Migration finished in {:.2?}.", started.elapsed()),
+            Err(e) => error!("Error occured during the migration {}", e),
+        }
+        info!("Watching the migration files for changes, press Ctrl-C to stop");
+
+        loop {
+            tokio::time::sleep(poll).await;
+            if configs.cancel.load(Ordering::SeqCst) {
+                return;
+            }
+            let files = watched_files(source);
+            if files != seen {
+                seen = files;
+                break;
+            }
+        }
 
-    match result {
-        Ok(_) => (),
-        Err(e) => return Err(AppError::DatabaseError(e.to_string()))
+        loop {
+            tokio::time::sleep(debounce).await;
+            if configs.cancel.load(Ordering::SeqCst) {
+                return;
+            }
+            let files = watched_files(source);
+            if files == seen {
+                break;
+            }
+            seen = files;
+        }
+
+        info!("{}", "-".repeat(60));
     }
+}
 
-    let mut in_database: HashMap<String, bool> = HashMap::new();
-    let mut rows = match conn.query("SELECT * FROM migrations", ()).await {
-        Ok(rows) => rows,
-        Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    // the env file can set the log level, so its error is reported once logging is up
+    let loaded = load_env_file();
+    init_logging(cli.verbose, color_enabled(&cli));
+    if let Err(e) = loaded {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    let file = match load_config_file() {
+        Ok(file) => file,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
     };
 
-    let mut name: String;
-    while let Some(row) = rows.next().await.unwrap() {
-        name = row.get_value(1).unwrap().as_text().unwrap().to_string();
-        in_database.insert(name, true);
+    // creating files doesn't need a database connection
+    if let Some(Command::Create { name, up_down, create_dir }) = &cli.command {
+        // new files go to the first folder when there are several
+        let path = match get_migrations_path(&cli, &file) {
+            Ok(path) => path,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        let path = env::split_paths(&path).next().unwrap_or_default();
+        match create_migration(&path.to_string_lossy(), name, *up_down, *create_dir) {
+            Ok(files) => {
+                for file in files {
+                    info!("Created migration file {}", file.display());
+                }
+            }
+            Err(e) => {
+                error!("Error while creating the migration {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
     }
 
-    let mut name: String;
-    let mut migration_content: String;
-    for file in list_files {
-        name = match file.file_name() {
-            Some(n) => n.to_str().unwrap().to_string(),
-            None => {
-                println!("cannot find name in path");
-                continue;
+    if let Some(Command::Init) = cli.command {
+        let path = match get_migrations_path(&cli, &file) {
+            Ok(path) => path,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
             }
         };
+        let path = env::split_paths(&path).next().unwrap_or_default();
+        match init_migrations(&path.to_string_lossy()) {
+            Ok(sample) => {
+                info!("Created migration file {}", sample.display());
+                info!("Next steps:");
+                info!("  1. Edit it or add files with `trso-migrator create <name>`");
+                info!("  2. Set TRSO_DSN, or TRSO_PATH_URL and TRSO_TOKEN, to the database");
+                info!("  3. Preview with `TRSO_DRY_RUN=true trso-migrator`, then run `trso-migrator`");
+            }
+            Err(e) => {
+                error!("Error while creating the migrations folder {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-        if let Some(_) = in_database.get(&name) {
-            println!("skipping file {}, it is already applied", name);
-            continue;
+    // the first database of a list that resolves stands in for the settings
+    // shared by all, one with a malformed DSN fails on its own in migrate_all.
+    // stdin is only read when there is no list and only once
+    let dsns = dsn_list(&file);
+    let piped = if dsns.is_empty() && dsn_from_stdin(&cli) {
+        match read_dsn_stdin() {
+            Ok(dsn) => Some(dsn),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let first = dsns.first().or(piped.as_ref()).map(String::as_str);
+    let mut resolved = get_configs(&cli, &file, first);
+    for dsn in dsns.iter().skip(1) {
+        if resolved.is_ok() {
+            break;
+        }
+        if let Ok(configs) = get_configs(&cli, &file, Some(dsn)) {
+            resolved = Ok(configs);
         }
+    }
+    let configs = match resolved {
+        Ok(configs) => configs,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if cli.print_config {
+        println!("{:#?}", configs);
+        return;
+    }
 
-        migration_content = match std::fs::read_to_string(file) {
-            Ok(content) => content,
-            Err(e) => return Err(AppError::IOError(e.to_string())),
+    if let Some(rule) = &cli.fail_on_missing_down {
+        let pairing = match rule.as_str() {
+            "all" => DownPairing::All,
+            _ => DownPairing::UpFiles,
         };
+        let source = migration_source(&cli, &file, &configs);
+        match check_down_files(&configs, source.as_ref(), pairing) {
+            Ok(count) => info!("Checked the rollback files of {} migration(s).", count),
+            Err(e) => {
+                error!("Error occured while checking the rollback files {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-        let transaction = conn.transaction().await.unwrap();
-        match transaction.execute_batch(&migration_content).await {
-            Ok(_) => {
-                let _ = conn.execute(
-                    "INSERT INTO migrations (file_name) VALUES (?1)",
-                    [name.as_str()],
-                )
-                .await;
-                let _ = transaction.commit().await;
-                println!("Migration applied for file {}", name);
+    if let Some(Command::Checksums { json, out }) = &cli.command {
+        let source = migration_source(&cli, &file, &configs);
+        let json = *json || configs.output == OutputFormat::Json;
+        let manifest = match checksum_manifest(source.as_ref(), json) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                error!("Error occured while reading the migrations {}", e);
+                std::process::exit(1);
             }
+        };
+        match out {
+            Some(path) => {
+                if let Err(e) = std::fs::write(path, manifest) {
+                    error!("Error occured while writing {} {}", path.display(), e);
+                    std::process::exit(1);
+                }
+                info!("Checksums written to {}", path.display());
+            }
+            None => print!("{}", manifest),
+        }
+        return;
+    }
+
+    if let Some(Command::Validate) = cli.command {
+        let source = migration_source(&cli, &file, &configs);
+        match validate_migrations(&configs, source.as_ref()).await {
+            Ok(count) => info!("Validated {} migration(s).", count),
             Err(e) => {
-                let _ = transaction.rollback().await;
-                println!("Error while executing migration {}", name);
-                return Err(AppError::DatabaseError(e.to_string()));
+                error!("Error occured while validating {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if cli.watch {
+        let other = cli.command.is_some()
+            || cli.force_reapply.is_some()
+            || matches!(configs.direction, Direction::Down);
+        if other || dsns.len() > 1 {
+            error!("--watch only applies migrations to one database, it can't be combined with other commands");
+            std::process::exit(1);
+        }
+        if !configs.local || env::var("TRSO_MIGRATIONS_ARCHIVE").is_ok() {
+            error!("--watch only runs against a local database with a migrations folder");
+            std::process::exit(1);
+        }
+    }
+
+    if cli.output_sql.is_some() {
+        let other = cli.command.is_some()
+            || cli.force_reapply.is_some()
+            || matches!(configs.direction, Direction::Down);
+        if other || dsns.len() > 1 {
+            error!("--output-sql only compiles the pending migrations of one database, it can't be combined with other commands");
+            std::process::exit(1);
+        }
+    }
+
+    // every other command reads or writes the rows of the migrations table
+    if configs.tracking == Tracking::UserVersion {
+        let other = cli.command.is_some()
+            || cli.force_reapply.is_some()
+            || cli.assume_applied_up_to.is_some()
+            || cli.output_sql.is_some()
+            || matches!(configs.direction, Direction::Down);
+        if other {
+            error!("TRSO_TRACKING=user_version only applies migrations, it can't be combined with other commands");
+            std::process::exit(1);
+        }
+    }
+
+    if dsns.len() > 1 {
+        migrate_all(&cli, &file, &dsns).await;
+        return;
+    }
+
+    // a remote database is refused before it is even connected to
+    if let Some(Command::Reset { force_remote }) = cli.command {
+        if (!configs.local || configs.replica_path.is_some()) && !force_remote {
+            error!(
+                "Refusing to reset the remote database {}, pass --force-remote to reset it anyway",
+                configs.url_or_path
+            );
+            std::process::exit(1);
+        }
+    }
+
+    let (db, conn) = match connect(&configs).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            let report = ErrorReport {
+                kind: "connection",
+                ..ErrorReport::new(&AppError::DatabaseError(e.to_string()))
+            };
+            fail(&configs, "Error while connecting to the database", report);
+        }
+    };
+
+    if let Some(Command::Ping) = cli.command {
+        // an embedded replica answers locally, only a sync reaches the primary
+        if configs.replica_path.is_some() {
+            if let Err(e) = sync_replica(&db, &configs).await {
+                error!("Error while connecting to the database {}", e);
+                std::process::exit(1);
+            }
+        }
+        info!("Connected to the database.");
+        return;
+    }
+
+    // pull the primary's state so pending migrations are computed correctly
+    if configs.replica_path.is_some() {
+        if let Err(e) = sync_before_run(&db, &configs).await {
+            fail(&configs, "Error occured during the migration", ErrorReport::new(&e));
+        }
+    }
+
+    if let Some(Command::ListApplied { json }) = cli.command {
+        let json = json || configs.output == OutputFormat::Json;
+        if let Err(e) = print_applied(&conn, &configs, json).await {
+            error!("Error occured while reading the applied migrations {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::DumpSchema { out }) = &cli.command {
+        let schema = match dump_schema(&conn).await {
+            Ok(schema) => schema,
+            Err(e) => {
+                error!("Error occured while reading the schema {}", e);
+                std::process::exit(1);
             }
         };
+        match out {
+            Some(path) => {
+                if let Err(e) = std::fs::write(path, schema) {
+                    error!("Error occured while writing {} {}", path.display(), e);
+                    std::process::exit(1);
+                }
+                info!("Schema written to {}", path.display());
+            }
+            None => print!("{}", schema),
+        }
+        return;
     }
 
-    return Ok(());
-}
+    // the commands above don't read the migration files
+    let source = migration_source(&cli, &file, &configs);
 
+    if let Some(Command::Status) = cli.command {
+        if let Err(e) = print_status(&conn, &configs, source.as_ref()).await {
+            error!("Error occured while reading the status {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-#[tokio::main]
-async fn main() {
-    let configs = get_configs();
-    let conn = get_connection(&configs).await.unwrap();
-    
-    println!("Migration is starting ...");
-    let result = migrate_database(&conn, configs.migrations_path).await;
+    // --assume-applied-up-to is `baseline --force` spelled as an option
+    let baseline = match (&cli.command, &cli.assume_applied_up_to) {
+        (Some(Command::Baseline { file_name, force }), _) => Some((file_name, *force)),
+        (None, Some(file_name)) => Some((file_name, true)),
+        _ => None,
+    };
+    if let Some((file_name, force)) = baseline {
+        match baseline_database(&conn, &configs, source.as_ref(), file_name, force).await {
+            Ok(count) => info!("Baselined {} migration(s).", count),
+            Err(e) => {
+                error!("Error occured while baselining {}", e);
+                std::process::exit(1);
+            }
+        }
+        if configs.replica_path.is_some() && !configs.dry_run {
+            if let Err(e) = sync_replica(&db, &configs).await {
+                error!("Error occured while baselining {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mark = match &cli.command {
+        Some(Command::Mark { file_name }) => Some((file_name, true)),
+        Some(Command::Unmark { file_name }) => Some((file_name, false)),
+        _ => None,
+    };
+    if let Some((file_name, applied)) = mark {
+        let result = if applied {
+            mark_database(&conn, &configs, source.as_ref(), file_name).await
+        } else {
+            unmark_database(&conn, &configs, source.as_ref(), file_name).await
+        };
+        if let Err(e) = result {
+            error!("Error occured while updating the migrations table {}", e);
+            std::process::exit(1);
+        }
+        if configs.replica_path.is_some() && !configs.dry_run {
+            if let Err(e) = sync_replica(&db, &configs).await {
+                error!("Error occured while updating the migrations table {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Repair { fix, remove_missing }) = cli.command {
+        let discrepancies = match find_discrepancies(&conn, &configs, source.as_ref()).await {
+            Ok(discrepancies) => discrepancies,
+            Err(e) => {
+                error!("Error occured while comparing the migrations table {}", e);
+                std::process::exit(1);
+            }
+        };
+        for name in &discrepancies.missing_files {
+            println!("{}: recorded as applied, but the file is missing", name);
+        }
+        for name in &discrepancies.out_of_order {
+            println!("{}: pending, but sorts before applied migrations", name);
+        }
+        for name in &discrepancies.missing_checksums {
+            println!("{}: applied without a checksum", name);
+        }
+        if discrepancies.is_empty() {
+            info!("The migrations table matches the migration files.");
+            return;
+        }
+        // out of order files are left to a normal run, they may be applied on purpose
+        if !fix {
+            if !discrepancies.missing_checksums.is_empty()
+                || !discrepancies.missing_files.is_empty()
+            {
+                info!("Run repair --fix to record the missing checksums, add --remove-missing to delete the records of missing files.");
+            }
+            return;
+        }
+
+        let question = format!(
+            "Delete the records of {} migration(s) whose file is missing?",
+            discrepancies.missing_files.len()
+        );
+        let remove = remove_missing
+            && !discrepancies.missing_files.is_empty()
+            && (configs.dry_run || assume_yes(&cli) || confirm(&question));
+        match repair_database(&conn, &configs, source.as_ref(), remove).await {
+            Ok(count) if configs.dry_run => info!("{} row(s) would be repaired.", count),
+            Ok(count) => info!("Repaired {} row(s) of the migrations table.", count),
+            Err(e) => {
+                error!("Error occured while repairing the migrations table {}", e);
+                std::process::exit(1);
+            }
+        }
+        if configs.replica_path.is_some() && !configs.dry_run {
+            if let Err(e) = sync_replica(&db, &configs).await {
+                error!("Error occured while repairing the migrations table {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Reset { .. }) = cli.command {
+        let question = format!(
+            "Drop every table of {} and apply all migrations again?",
+            configs.url_or_path
+        );
+        if !configs.dry_run && !assume_yes(&cli) && !confirm(&question) {
+            info!("Nothing was reset.");
+            return;
+        }
 
-    match result {
-        Ok(_) => {},
-        Err(e) => println!("Error occured during the migration {:?}", e)
+        match reset_database(&conn, &configs).await {
+            Ok(count) if configs.dry_run => info!("{} object(s) would be dropped.", count),
+            Ok(count) => info!("Dropped {} object(s).", count),
+            Err(e) => {
+                error!("Error occured while resetting {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        // the pending migrations of a dry run would still be computed from the
+        // tables that weren't dropped
+        if configs.dry_run {
+            return;
+        }
     }
 
-    println!("Migration finished.");
+    if let Some(file_name) = &cli.force_reapply {
+        let question = format!("Reapply the already applied migration {}?", file_name);
+        if !configs.dry_run && !assume_yes(&cli) && !confirm(&question) {
+            info!("Nothing was reapplied.");
+            return;
+        }
+    }
+
+    cancel_on_ctrl_c(configs.cancel.clone());
+
+    if cli.watch {
+        watch(&conn, &configs, source.as_ref()).await;
+        return;
+    }
+
+    let plain_run = cli.command.is_none() && cli.force_reapply.is_none();
+    if plain_run
+        && matches!(configs.direction, Direction::Up)
+        && !confirm_remote(&cli, &conn, &configs, source.as_ref()).await
+    {
+        info!("Nothing was applied.");
+        return;
+    }
+
+    info!("Migration is starting ...");
+    let started = Instant::now();
+    let result = match (&cli.command, &cli.force_reapply, &configs.direction) {
+        (_, Some(file_name), _) => {
+            reapply_database(&conn, &configs, source.as_ref(), file_name).await
+        }
+        (Some(Command::Reset { .. }), _, _) => migrate_database(&conn, &configs, source.as_ref())
+            .await
+            .map(|report| report.applied.len()),
+        (Some(Command::Redo), _, _) => redo_database(&conn, &configs, source.as_ref()).await,
+        (_, _, Direction::Up) => migrate_database(&conn, &configs, source.as_ref())
+            .await
+            .map(|report| report.applied.len()),
+        (_, _, Direction::Down) => rollback_database(&conn, &configs, source.as_ref()).await,
+    };
+
+    notify_webhook(&configs, &RunSummary::new(&configs, &result, started.elapsed())).await;
+    let pending = match result {
+        Ok(count) => count,
+        Err(e) => fail(&configs, "Error occured during the migration", ErrorReport::new(&e)),
+    };
+
+    if configs.replica_path.is_some() && !configs.dry_run {
+        if let Err(e) = sync_replica(&db, &configs).await {
+            let context = format!(
+                "{} migration(s) were applied to the replica, but not synced to the primary,",
+                pending
+            );
+            fail(&configs, &context, ErrorReport::new(&e));
+        }
+    }
+
+    info!("Migration finished in {:.2?}.", started.elapsed());
+
+    // dry run exits with 2 when there is work to do, so CI can gate on it
+    if configs.dry_run && configs.output_sql.is_none() && pending > 0 {
+        std::process::exit(2);
+    }
 }
@@ -0,0 +1,115 @@
+//! Shows the schema changes of pending migrations in dry run mode.
+
+use std::collections::BTreeMap;
+
+use libsql::{Builder, Connection, Database};
+
+use crate::{execute_sql, AppError, Config};
+
+/// An in-memory copy of the schema of the migrated database, the pending
+/// migrations are applied to it one after another to see what they change.
+pub(crate) struct SchemaPreview {
+    // the connection only lives as long as its database
+    _db: Database,
+
+    conn: Connection,
+}
+
+impl SchemaPreview {
+    /// Copies the tables, indexes, views and triggers of `source`, no rows.
+    pub(crate) async fn new(source: &Connection) -> Result<SchemaPreview, AppError> {
+        let db = match Builder::new_local(":memory:").build().await {
+            Ok(db) => db,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+        let conn = match db.connect() {
+            Ok(conn) => conn,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+
+        let sql = "SELECT sql FROM sqlite_master WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%' ORDER BY rowid";
+        let mut rows = match source.query(sql, ()).await {
+            Ok(rows) => rows,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+
+        loop {
+            let row = match rows.next().await {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+            };
+
+            let definition = match row.get::<String>(0) {
+                Ok(definition) => definition,
+                Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+            };
+            if let Err(e) = conn.execute(&definition, ()).await {
+                return Err(AppError::DatabaseError(e.to_string()));
+            }
+        }
+
+        Ok(SchemaPreview { _db: db, conn })
+    }
+
+    /// Applies a migration to the copy and returns the changed definitions,
+    /// `+` for created objects, `-` for dropped and `~` for changed ones.
+    pub(crate) async fn apply(
+        &self,
+        name: &str,
+        content: &str,
+        config: &Config,
+    ) -> Result<Vec<String>, AppError> {
+        let before = self.schema().await?;
+        execute_sql(&self.conn, name, content, config).await?;
+        let after = self.schema().await?;
+
+        let mut changes = Vec::new();
+        for (object, (kind, definition)) in &before {
+            match after.get(object) {
+                None => changes.push(format!("- {} {}", kind, object)),
+                Some((_, changed)) if changed != definition => {
+                    changes.push(format!("~ {}", changed))
+                }
+                Some(_) => (),
+            }
+        }
+        for (object, (_, definition)) in &after {
+            if !before.contains_key(object) {
+                changes.push(format!("+ {}", definition));
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Type and definition of every object keyed by name.
+    async fn schema(&self) -> Result<BTreeMap<String, (String, String)>, AppError> {
+        let sql = "SELECT name, type, sql FROM sqlite_master WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%'";
+        let mut rows = match self.conn.query(sql, ()).await {
+            Ok(rows) => rows,
+            Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+        };
+
+        let mut schema = BTreeMap::new();
+        loop {
+            let row = match rows.next().await {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => return Err(AppError::DatabaseError(e.to_string())),
+            };
+
+            let object = (row.get::<String>(0), row.get::<String>(1), row.get::<String>(2));
+            match object {
+                (Ok(name), Ok(kind), Ok(definition)) => {
+                    schema.insert(name, (kind, definition));
+                }
+                (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                    return Err(AppError::DatabaseError(e.to_string()))
+                }
+            }
+        }
+
+        Ok(schema)
+    }
+}
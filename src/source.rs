@@ -0,0 +1,503 @@
+//! Where the migration files are read from.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{AppError, Config};
+
+/// A `.sql` or gzipped `.sql.gz` file of a [`MigrationSource`].
+#[derive(Debug, Clone)]
+pub struct MigrationEntry {
+    /// Name the migration is recorded by, e.g. `001_users.sql`. Files in
+    /// subfolders are named by their relative path joined with `/`, gzipped
+    /// files without the `.gz`.
+    pub name: String,
+
+    /// Where the source finds the content, the file path for folders on disk.
+    pub location: PathBuf,
+}
+
+/// Lists and reads migration files. Rollback scripts (`.down.sql`) and hooks
+/// are listed like any other file and told apart by their names.
+pub trait MigrationSource: Send + Sync {
+    /// Every `.sql` file of the source, in any order.
+    fn list(&self) -> Result<Vec<MigrationEntry>, AppError>;
+
+    /// Content of an entry returned by `list`, decompressed for `.sql.gz` files.
+    fn content(&self, entry: &MigrationEntry) -> Result<String, AppError>;
+}
+
+/// Migration folders on disk, several folders are combined into one list.
+#[derive(Debug)]
+pub struct FsSource {
+    dirs: Vec<PathBuf>,
+
+    recursive: bool,
+}
+
+impl FsSource {
+    /// Reads the `.sql` files of `dirs`, with `recursive` of their subfolders too.
+    pub fn new(dirs: Vec<PathBuf>, recursive: bool) -> FsSource {
+        FsSource { dirs, recursive }
+    }
+
+    /// The folders of `config.migrations_path`, which holds one or more paths
+    /// separated like `PATH`, e.g. `shared/migrations:app/migrations` on unix.
+    pub fn from_config(config: &Config) -> FsSource {
+        FsSource::new(
+            std::env::split_paths(&config.migrations_path).collect(),
+            config.recursive,
+        )
+    }
+}
+
+impl MigrationSource for FsSource {
+    /// A name existing in more than one folder is an error.
+    fn list(&self) -> Result<Vec<MigrationEntry>, AppError> {
+        let mut files = Vec::new();
+        for dir in &self.dirs {
+            check_dir(dir)?;
+            collect_sql_files(dir, dir, self.recursive, &mut files)?;
+        }
+
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for pair in files.windows(2) {
+            if pair[0].name == pair[1].name {
+                return Err(AppError::IOError(format!(
+                    "migration {} exists more than once: {} and {}",
+                    pair[0].name,
+                    pair[0].location.display(),
+                    pair[1].location.display()
+                )));
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn content(&self, entry: &MigrationEntry) -> Result<String, AppError> {
+        match std::fs::read(&entry.location) {
+            Ok(bytes) => decode(&entry.name, &entry.location, bytes),
+            Err(e) => Err(AppError::IOError(e.to_string())),
+        }
+    }
+}
+
+/// Tells a missing migrations folder apart from a file in its place before
+/// anything is read, with a hint where the path comes from.
+fn check_dir(dir: &Path) -> Result<(), AppError> {
+    match std::fs::metadata(dir) {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => Err(AppError::IOError(format!(
+            "migrations path {} is not a folder, set TRSO_MIGRATIONS_PATH to the folder with the migration files",
+            dir.display()
+        ))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(AppError::IOError(format!(
+            "migrations folder {} does not exist, set TRSO_MIGRATIONS_PATH to the folder with the migration files",
+            dir.display()
+        ))),
+        Err(e) => Err(read_error(dir, e)),
+    }
+}
+
+fn read_error(dir: &Path, e: io::Error) -> AppError {
+    if e.kind() == io::ErrorKind::PermissionDenied {
+        return AppError::IOError(format!(
+            "cannot read the migrations folder {}, permission denied",
+            dir.display()
+        ));
+    }
+
+    AppError::IOError(format!(
+        "cannot read the migrations folder {}: {}",
+        dir.display(),
+        e
+    ))
+}
+
+fn read_dir_paths(dir: &Path) -> Result<Vec<PathBuf>, AppError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return Err(read_error(dir, e)),
+    };
+
+    match entries
+        .map(|res| res.map(|e| e.path()))
+        .collect::<Result<Vec<_>, io::Error>>()
+    {
+        Ok(list) => Ok(list),
+        Err(e) => Err(read_error(dir, e)),
+    }
+}
+
+/// Collects the `.sql` files of `dir` with their paths relative to `root`,
+/// joined with `/` so the recorded names are the same on every platform.
+/// Dotfiles such as `.DS_Store` and editor swap files are skipped.
+fn collect_sql_files(
+    root: &Path,
+    dir: &Path,
+    recursive: bool,
+    files: &mut Vec<MigrationEntry>,
+) -> Result<(), AppError> {
+    for file in read_dir_paths(dir)? {
+        if is_hidden(&file) {
+            continue;
+        }
+
+        if file.is_dir() {
+            if recursive {
+                collect_sql_files(root, &file, recursive, files)?;
+            }
+            continue;
+        }
+
+        if !is_sql(&file) {
+            continue;
+        }
+
+        files.push(MigrationEntry {
+            name: without_gz(relative_name(root, &file)),
+            location: file,
+        });
+    }
+
+    Ok(())
+}
+
+fn is_hidden(file: &Path) -> bool {
+    file.file_name()
+        .and_then(|name| name.to_str())
+        .is_none_or(|name| name.starts_with('.'))
+}
+
+fn is_sql(file: &Path) -> bool {
+    file.to_str()
+        .is_some_and(|file| file.ends_with(".sql") || file.ends_with(".sql.gz"))
+}
+
+fn is_gzipped(file: &Path) -> bool {
+    file.extension().and_then(|ext| ext.to_str()) == Some("gz")
+}
+
+/// Gzipped files are recorded like the `.sql` file they contain.
+fn without_gz(name: String) -> String {
+    match name.strip_suffix(".gz") {
+        Some(name) => name.to_string(),
+        None => name,
+    }
+}
+
+/// Content of the file at `location`, gzipped files are decompressed in memory.
+/// A leading UTF-8 byte order mark is dropped, SQLite would take it as part of
+/// the first statement.
+fn decode(name: &str, location: &Path, bytes: Vec<u8>) -> Result<String, AppError> {
+    let bytes = if is_gzipped(location) {
+        let mut content = Vec::new();
+        let mut decoder = flate2::read::GzDecoder::new(bytes.as_slice());
+        if let Err(e) = decoder.read_to_end(&mut content) {
+            return Err(AppError::IOError(format!(
+                "cannot decompress migration {}: {}",
+                name, e
+            )));
+        }
+        content
+    } else {
+        bytes
+    };
+
+    match String::from_utf8(bytes) {
+        Ok(content) => match content.strip_prefix('\u{feff}') {
+            Some(content) => Ok(content.to_string()),
+            None => Ok(content),
+        },
+        Err(e) => Err(AppError::IOError(format!(
+            "migration {} is not valid UTF-8, the byte at offset {} is invalid",
+            name,
+            e.utf8_error().valid_up_to()
+        ))),
+    }
+}
+
+fn relative_name(root: &Path, file: &Path) -> String {
+    file.strip_prefix(root)
+        .unwrap_or(file)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Migrations read from a `.zip`, `.tar`, `.tar.gz` or `.tgz` archive, named by
+/// their path inside the archive. The whole archive is read when it is opened.
+#[derive(Debug)]
+pub struct ArchiveSource {
+    files: HashMap<String, String>,
+}
+
+impl ArchiveSource {
+    /// Reads the `.sql` files of the archive at `path`, with `recursive` those
+    /// in its folders too. Its extension decides the format.
+    pub fn open(path: &Path, recursive: bool) -> Result<ArchiveSource, AppError> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                return Err(AppError::IOError(format!(
+                    "cannot open the migrations archive {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+        };
+
+        let name = path.to_string_lossy().to_lowercase();
+        let entries = if name.ends_with(".zip") {
+            read_zip(file)
+        } else if name.ends_with(".tar") {
+            read_tar(file)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            read_tar(flate2::read::GzDecoder::new(file))
+        } else {
+            return Err(AppError::ConfigError(format!(
+                "unsupported migrations archive {}, expected .zip, .tar, .tar.gz or .tgz",
+                path.display()
+            )));
+        };
+
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(e) => {
+                return Err(AppError::IOError(format!(
+                    "cannot read the migrations archive {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+        };
+
+        let mut files = HashMap::new();
+        for (entry, content) in entries {
+            let entry_path = Path::new(&entry);
+            // the same files as in a folder on disk, hidden folders are skipped too
+            let hidden = entry_path.components().any(|part| is_hidden(Path::new(&part)));
+            if hidden || !is_sql(entry_path) {
+                continue;
+            }
+            if !recursive && entry_path.components().count() > 1 {
+                continue;
+            }
+
+            let name = without_gz(relative_name(Path::new(""), entry_path));
+            let content = decode(&name, entry_path, content)?;
+            if files.insert(name.clone(), content).is_some() {
+                return Err(AppError::IOError(format!(
+                    "migration {} exists more than once in {}",
+                    name,
+                    path.display()
+                )));
+            }
+        }
+
+        Ok(ArchiveSource { files })
+    }
+}
+
+impl MigrationSource for ArchiveSource {
+    fn list(&self) -> Result<Vec<MigrationEntry>, AppError> {
+        let files = self
+            .files
+            .keys()
+            .map(|name| MigrationEntry {
+                name: name.clone(),
+                location: PathBuf::from(name),
+            })
+            .collect();
+
+        Ok(files)
+    }
+
+    fn content(&self, entry: &MigrationEntry) -> Result<String, AppError> {
+        // entries are renamed by the listing, the location keeps the archived name
+        match self.files.get(&*entry.location.to_string_lossy()) {
+            Some(content) => Ok(content.clone()),
+            None => Err(AppError::IOError(format!(
+                "{} is not an archived migration",
+                entry.name
+            ))),
+        }
+    }
+}
+
+/// Names and contents of the files of a zip archive, folders are skipped.
+fn read_zip(file: std::fs::File) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut archive = match zip::ZipArchive::new(file) {
+        Ok(archive) => archive,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+        let mut entry = match archive.by_index(index) {
+            Ok(entry) => entry,
+            Err(e) => return Err(e.to_string()),
+        };
+        if !entry.is_file() {
+            continue;
+        }
+
+        // entries escaping the archive with ../ can't be named safely
+        let name = match entry.enclosed_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => return Err(format!("invalid entry name {}", entry.name())),
+        };
+        let mut content = Vec::new();
+        if let Err(e) = entry.read_to_end(&mut content) {
+            return Err(e.to_string());
+        }
+        entries.push((name, content));
+    }
+
+    Ok(entries)
+}
+
+/// Names and contents of the regular files of a tar archive.
+fn read_tar(reader: impl Read) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut archive = tar::Archive::new(reader);
+    let list = match archive.entries() {
+        Ok(list) => list,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let mut entries = Vec::new();
+    for entry in list {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => return Err(e.to_string()),
+        };
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = match entry.path() {
+            Ok(path) => path.into_owned(),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        // like zip entries, names escaping the archive are refused and ./ dropped
+        let mut name = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => name.push(part),
+                Component::CurDir => (),
+                _ => return Err(format!("invalid entry name {}", path.display())),
+            }
+        }
+        let mut content = Vec::new();
+        if let Err(e) = entry.read_to_end(&mut content) {
+            return Err(e.to_string());
+        }
+        entries.push((name.to_string_lossy().to_string(), content));
+    }
+
+    Ok(entries)
+}
+
+/// Migrations compiled into the binary with `include_dir!`, listed and named
+/// exactly like the same folder on disk.
+#[cfg(feature = "embed")]
+#[derive(Debug)]
+pub struct EmbeddedSource {
+    dir: &'static include_dir::Dir<'static>,
+
+    recursive: bool,
+}
+
+#[cfg(feature = "embed")]
+impl EmbeddedSource {
+    pub fn new(dir: &'static include_dir::Dir<'static>, recursive: bool) -> EmbeddedSource {
+        EmbeddedSource { dir, recursive }
+    }
+}
+
+#[cfg(feature = "embed")]
+impl MigrationSource for EmbeddedSource {
+    fn list(&self) -> Result<Vec<MigrationEntry>, AppError> {
+        let mut files = Vec::new();
+        collect_embedded(self.dir, self.recursive, &mut files);
+
+        Ok(files)
+    }
+
+    fn content(&self, entry: &MigrationEntry) -> Result<String, AppError> {
+        let embedded = match self.dir.get_file(&entry.location) {
+            Some(embedded) => embedded,
+            None => {
+                return Err(AppError::IOError(format!(
+                    "{} is not an embedded migration",
+                    entry.name
+                )))
+            }
+        };
+
+        decode(&entry.name, &entry.location, embedded.contents().to_vec())
+    }
+}
+
+/// Paths of embedded entries are already relative to the embedded folder.
+#[cfg(feature = "embed")]
+fn collect_embedded(
+    dir: &'static include_dir::Dir<'static>,
+    recursive: bool,
+    files: &mut Vec<MigrationEntry>,
+) {
+    for entry in dir.entries() {
+        if is_hidden(entry.path()) {
+            continue;
+        }
+
+        match entry {
+            include_dir::DirEntry::Dir(sub) => {
+                if recursive {
+                    collect_embedded(sub, recursive, files);
+                }
+            }
+            include_dir::DirEntry::File(file) => {
+                if is_sql(file.path()) {
+                    files.push(MigrationEntry {
+                        name: without_gz(relative_name(Path::new(""), file.path())),
+                        location: file.path().to_path_buf(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_a_leading_byte_order_mark() {
+        let bytes = b"\xef\xbb\xbfCREATE TABLE users (id INTEGER);".to_vec();
+
+        let content = decode("001_users.sql", Path::new("001_users.sql"), bytes).unwrap();
+        assert_eq!(content, "CREATE TABLE users (id INTEGER);");
+    }
+
+    #[test]
+    fn reports_the_offset_of_an_invalid_byte() {
+        let bytes = b"SELECT '\xff';".to_vec();
+
+        match decode("001_users.sql", Path::new("001_users.sql"), bytes) {
+            Err(AppError::IOError(message)) => {
+                assert!(message.contains("001_users.sql"));
+                assert!(message.contains("offset 8"));
+            }
+            other => panic!("expected an invalid UTF-8 error, got {:?}", other),
+        }
+    }
+}
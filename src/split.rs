@@ -0,0 +1,320 @@
+//! Splits a migration file into the statements it is made of.
+
+/// Splits a migration file into statements like [`split_statements`], except
+/// for blocks after a `-- trso:delimiter <separator>` line, which are split on
+/// every occurrence of that separator instead. `-- trso:delimiter ;` switches
+/// back to the default splitting, like `DELIMITER` in the MySQL client.
+pub(crate) fn split_file(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut delimiter = String::from(";");
+    let mut block = String::new();
+
+    for line in sql.split_inclusive('\n') {
+        match delimiter_directive(line) {
+            Some(next) => {
+                split_block(&block, &delimiter, &mut statements);
+                block.clear();
+                delimiter = next.to_string();
+            }
+            None => block.push_str(line),
+        }
+    }
+    split_block(&block, &delimiter, &mut statements);
+
+    statements
+}
+
+fn split_block(block: &str, delimiter: &str, statements: &mut Vec<String>) {
+    if delimiter == ";" {
+        statements.extend(split_statements(block));
+        return;
+    }
+
+    for statement in block.split(delimiter) {
+        // pieces with nothing but comments in them aren't statements
+        if !split_statements(statement).is_empty() {
+            statements.push(statement.trim().to_string());
+        }
+    }
+}
+
+/// The separator of a `-- trso:delimiter <separator>` line.
+fn delimiter_directive(line: &str) -> Option<&str> {
+    let rest = line.trim().strip_prefix("--")?.trim_start();
+    let separator = rest.strip_prefix("trso:delimiter")?;
+    if !separator.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    Some(separator.trim()).filter(|separator| !separator.is_empty())
+}
+
+/// Splits `sql` on `;` outside of string literals, quoted identifiers,
+/// comments and `CREATE TRIGGER ... BEGIN ... END` bodies. The final statement
+/// doesn't need a trailing `;`, fragments with only comments are dropped.
+pub(crate) fn split_statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    // whether the current statement has anything besides comments and whitespace
+    let mut has_code = false;
+
+    // leading keywords of the current statement, used to detect triggers
+    let mut keywords: Vec<String> = Vec::new();
+    let mut word = String::new();
+    // the trigger body and the CASE ... END nested in it
+    let mut depth: usize = 0;
+    // the last character outside of comments and whitespace
+    let mut previous = ' ';
+
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            word.push(c);
+            current.push(c);
+            has_code = true;
+            continue;
+        }
+
+        if !word.is_empty() {
+            track_keyword(&word, previous, c, &mut keywords, &mut depth);
+            previous = 'a';
+            word.clear();
+        }
+
+        match c {
+            '\'' | '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                current.push(c);
+                has_code = true;
+                // a doubled quote is an escaped quote, which simply closes and
+                // reopens the literal here
+                for next in chars.by_ref() {
+                    current.push(next);
+                    if next == close {
+                        break;
+                    }
+                }
+                previous = close;
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                current.push(c);
+                for next in chars.by_ref() {
+                    current.push(next);
+                    if next == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                current.push(c);
+                current.push(chars.next().unwrap());
+                let mut previous = ' ';
+                for next in chars.by_ref() {
+                    current.push(next);
+                    if previous == '*' && next == '/' {
+                        break;
+                    }
+                    previous = next;
+                }
+            }
+            ';' if depth == 0 => {
+                if has_code {
+                    statements.push(current.trim().to_string());
+                }
+                current.clear();
+                has_code = false;
+                keywords.clear();
+                previous = c;
+            }
+            _ => {
+                current.push(c);
+                if !c.is_whitespace() {
+                    has_code = true;
+                    previous = c;
+                }
+            }
+        }
+    }
+
+    if !word.is_empty() {
+        track_keyword(&word, previous, ' ', &mut keywords, &mut depth);
+    }
+
+    if has_code {
+        statements.push(current.trim().to_string());
+    }
+
+    statements
+}
+
+/// Follows the BEGIN/CASE ... END nesting once the statement turned out to be
+/// a trigger, `before` and `after` are the characters around `word`.
+fn track_keyword(
+    word: &str,
+    before: char,
+    after: char,
+    keywords: &mut Vec<String>,
+    depth: &mut usize,
+) {
+    let word = word.to_ascii_uppercase();
+    if keywords.len() < 3 {
+        keywords.push(word.clone());
+    }
+
+    // qualified names like new.end or end.id are columns and tables
+    if !is_trigger(keywords) || before == '.' || after == '.' {
+        return;
+    }
+
+    match word.as_str() {
+        "BEGIN" if *depth == 0 => *depth = 1,
+        "CASE" if *depth > 0 => *depth += 1,
+        // every statement of the body ends with `;`, so only the END right
+        // after one closes the body, a column named end never stands there
+        "END" if *depth == 1 && before == ';' => *depth = 0,
+        "END" if *depth > 1 => *depth -= 1,
+        _ => (),
+    }
+}
+
+/// `CREATE [TEMP | TEMPORARY] TRIGGER`
+fn is_trigger(keywords: &[String]) -> bool {
+    match keywords {
+        [create, trigger, ..] if create == "CREATE" && trigger == "TRIGGER" => true,
+        [create, temp, trigger] => {
+            create == "CREATE" && (temp == "TEMP" || temp == "TEMPORARY") && trigger == "TRIGGER"
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_on_semicolons() {
+        let statements = split_statements("CREATE TABLE a (id INTEGER);\nCREATE TABLE b (id INTEGER);\n");
+
+        assert_eq!(
+            statements,
+            ["CREATE TABLE a (id INTEGER)", "CREATE TABLE b (id INTEGER)"]
+        );
+    }
+
+    #[test]
+    fn keeps_semicolons_in_string_literals() {
+        let sql = "INSERT INTO notes (body) VALUES ('a; b');\nINSERT INTO notes (body) VALUES ('it''s; here');";
+
+        assert_eq!(
+            split_statements(sql),
+            [
+                "INSERT INTO notes (body) VALUES ('a; b')",
+                "INSERT INTO notes (body) VALUES ('it''s; here')"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_semicolons_in_quoted_identifiers_and_comments() {
+        let sql = "-- first; still a comment\nCREATE TABLE \"a;b\" (id INTEGER); /* a; b */ SELECT 1;";
+
+        let statements = split_statements(sql);
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].ends_with("CREATE TABLE \"a;b\" (id INTEGER)"));
+        assert!(statements[1].ends_with("SELECT 1"));
+    }
+
+    #[test]
+    fn keeps_trigger_bodies_together() {
+        let sql = "CREATE TRIGGER touch AFTER UPDATE ON users BEGIN\n    UPDATE users SET updated_at = 1 WHERE id = new.id;\n    INSERT INTO audit (user_id) VALUES (new.id);\nEND;\nSELECT 1;";
+
+        let statements = split_statements(sql);
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].starts_with("CREATE TRIGGER touch"));
+        assert!(statements[0].ends_with("END"));
+        assert_eq!(statements[1], "SELECT 1");
+    }
+
+    #[test]
+    fn keeps_case_inside_temp_trigger_bodies() {
+        let sql = "CREATE TEMP TRIGGER t AFTER INSERT ON a BEGIN\n    UPDATE a SET kind = CASE WHEN new.id > 1 THEN 'b' ELSE 'c' END;\nEND;";
+
+        let statements = split_statements(sql);
+        assert_eq!(statements.len(), 1);
+        assert!(statements[0].ends_with("END"));
+    }
+
+    #[test]
+    fn begin_outside_of_a_trigger_is_a_statement() {
+        assert_eq!(split_statements("BEGIN; SELECT 1; END;"), ["BEGIN", "SELECT 1", "END"]);
+    }
+
+    #[test]
+    fn delimiter_directive_keeps_trigger_together() {
+        let sql = "CREATE TABLE audit (id INTEGER);\n-- trso:delimiter $$\nCREATE TRIGGER log AFTER INSERT ON audit BEGIN\n    INSERT INTO audit (id) VALUES (1);\n    INSERT INTO audit (id) VALUES (2);\nEND$$\n-- trso:delimiter ;\nSELECT 1; SELECT 2;\n";
+
+        let statements = split_file(sql);
+        assert_eq!(statements.len(), 4);
+        assert_eq!(statements[0], "CREATE TABLE audit (id INTEGER)");
+        assert!(statements[1].starts_with("CREATE TRIGGER log"));
+        assert_eq!(statements[1].matches(';').count(), 2);
+        assert!(statements[1].ends_with("END"));
+        assert_eq!(&statements[2..], ["SELECT 1", "SELECT 2"]);
+    }
+
+    #[test]
+    fn delimiter_directive_needs_a_separator() {
+        assert_eq!(delimiter_directive("-- trso:delimiter $$\n"), Some("$$"));
+        assert_eq!(delimiter_directive("-- trso:delimiter\n"), None);
+        assert_eq!(delimiter_directive("-- trso:delimiters $$\n"), None);
+    }
+
+    #[test]
+    fn last_statement_needs_no_semicolon() {
+        assert_eq!(split_file("SELECT 1;\nSELECT 2\n"), ["SELECT 1", "SELECT 2"]);
+    }
+
+    #[test]
+    fn empty_and_comment_only_files_have_no_statements() {
+        for sql in ["", "  \n\t\n", "-- nothing yet\n/* later; maybe */\n", ";\n;"] {
+            assert!(split_file(sql).is_empty(), "{:?} should have no statements", sql);
+        }
+    }
+
+    #[test]
+    fn columns_named_end_or_case_stay_in_the_trigger_body() {
+        let sql = "CREATE TRIGGER t AFTER INSERT ON a BEGIN\n    UPDATE a SET end = new.end, \"case\" = CASE WHEN new.\"end\" > 1 THEN 1 END WHERE id = new.id;\n    DELETE FROM end;\n    INSERT INTO \"end\" (\"end\") VALUES (end.id);\nEND;\nSELECT 1;";
+
+        let statements = split_statements(sql);
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].starts_with("CREATE TRIGGER t"));
+        assert_eq!(statements[0].matches(';').count(), 3);
+        assert!(statements[0].ends_with("END"));
+        assert_eq!(statements[1], "SELECT 1");
+    }
+
+    #[test]
+    fn tables_named_end_or_case_are_plain_statements() {
+        let sql = "CREATE TABLE \"end\" (\"case\" INTEGER);\nCREATE TABLE [case] (end INTEGER);\nSELECT end FROM \"end\";";
+
+        assert_eq!(
+            split_statements(sql),
+            [
+                "CREATE TABLE \"end\" (\"case\" INTEGER)",
+                "CREATE TABLE [case] (end INTEGER)",
+                "SELECT end FROM \"end\""
+            ]
+        );
+    }
+
+    #[test]
+    fn begin_transaction_of_a_no_transaction_file_is_a_statement() {
+        let sql = "-- trso:no-transaction\nBEGIN TRANSACTION;\nCREATE TABLE a (id INTEGER);\nCOMMIT;\n";
+
+        let statements = split_file(sql);
+        assert_eq!(statements.len(), 3);
+        assert!(statements[0].ends_with("BEGIN TRANSACTION"));
+        assert_eq!(&statements[1..], ["CREATE TABLE a (id INTEGER)", "COMMIT"]);
+    }
+}
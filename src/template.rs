@@ -0,0 +1,54 @@
+//! Replaces `${VAR}` placeholders in a migration with environment variables.
+
+/// Substitutes every `${NAME}` where `NAME` is a plain identifier with the value
+/// returned by `lookup`. Anything else, like a `$` without braces, is kept as it
+/// is. Returns the names of all unresolved variables when any are missing.
+pub(crate) fn render(
+    content: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, Vec<String>> {
+    let mut rendered = String::with_capacity(content.len());
+    let mut missing: Vec<String> = Vec::new();
+
+    let mut rest = content;
+    while let Some(start) = rest.find("${") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+
+        let name = match after.find('}') {
+            Some(end) if is_identifier(&after[..end]) => &after[..end],
+            _ => {
+                rendered.push_str("${");
+                rest = after;
+                continue;
+            }
+        };
+
+        match lookup(name) {
+            Some(value) => rendered.push_str(&value),
+            None => {
+                if !missing.iter().any(|m| m == name) {
+                    missing.push(name.to_string());
+                }
+            }
+        }
+        rest = &after[name.len() + 1..];
+    }
+    rendered.push_str(rest);
+
+    if missing.is_empty() {
+        Ok(rendered)
+    } else {
+        Err(missing)
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,307 @@
+//! Resolves a [`Config`] from `TRSO_*` variables.
+
+use std::{collections::HashMap, path::Path};
+
+use log::warn;
+
+use crate::{
+    parse_dsn, validate_table_name, AppError, Config, Direction, OutputFormat, Tracking,
+    DEFAULT_NAMING_PATTERN,
+};
+
+impl Config {
+    /// The configuration of the `TRSO_*` variables of the process environment.
+    pub fn from_env() -> Result<Config, AppError> {
+        Config::from_map(&std::env::vars().collect())
+    }
+
+    /// The configuration of the `TRSO_*` variables in `vars`, unset ones take
+    /// their defaults. A `TRSO_DSN` wins over `TRSO_PATH_URL`, `TRSO_TOKEN`
+    /// and `TRSO_LOCAL`. Token files are read right away.
+    pub fn from_map(vars: &HashMap<String, String>) -> Result<Config, AppError> {
+        let var = |name: &str| vars.get(name).cloned();
+
+        let mut read_your_writes = true;
+        let mut namespace = None;
+        let (local, url_or_path, token) = match vars.get("TRSO_DSN") {
+            Some(dsn) => {
+                let dsn = match parse_dsn(dsn) {
+                    Ok(dsn) => dsn,
+                    Err(AppError::ConfigError(message)) => {
+                        return Err(AppError::ConfigError(format!("invalid TRSO_DSN: {}", message)))
+                    }
+                    Err(e) => return Err(e),
+                };
+                for (key, _) in &dsn.params {
+                    if !matches!(
+                        key.as_str(),
+                        "tls" | "secure" | "readYourWrites" | "namespace"
+                    ) {
+                        warn!("Ignoring unsupported DSN parameter {}", key);
+                    }
+                }
+                read_your_writes = dsn.read_your_writes();
+                namespace = dsn.param("namespace").map(String::from);
+                // authToken=file:///run/secrets/token keeps the token out of the DSN
+                let token = match dsn.token {
+                    Some(token) => match token.strip_prefix("file://") {
+                        Some(path) => read_token_file(path)?,
+                        None => token,
+                    },
+                    None => String::new(),
+                };
+                (dsn.local, dsn.url_or_path, token)
+            }
+            None => {
+                let local = flag(vars, "TRSO_LOCAL")?;
+                let url_or_path = match var("TRSO_PATH_URL") {
+                    Some(url_or_path) => url_or_path,
+                    None => {
+                        return Err(AppError::ConfigError(String::from(
+                            "TRSO_DSN or TRSO_PATH_URL has to be set",
+                        )))
+                    }
+                };
+
+                let token = match (var("TRSO_TOKEN"), var("TRSO_TOKEN_FILE")) {
+                    _ if local => String::new(),
+                    (Some(token), _) => token,
+                    (None, Some(path)) => read_token_file(&path)?,
+                    (None, None) => {
+                        return Err(AppError::ConfigError(String::from(
+                            "if not TRSO_LOCAL=true, TRSO_TOKEN or TRSO_TOKEN_FILE must be set",
+                        )))
+                    }
+                };
+
+                (local, url_or_path, token)
+            }
+        };
+
+        let replica_path = var("TRSO_REPLICA_PATH");
+        if local && replica_path.is_some() {
+            return Err(AppError::ConfigError(String::from(
+                "TRSO_REPLICA_PATH needs a remote database, it can't be used with a local one",
+            )));
+        }
+
+        let direction = match var("TRSO_DIRECTION").as_deref() {
+            None | Some("up") => Direction::Up,
+            Some("down") => Direction::Down,
+            Some(_) => {
+                return Err(AppError::ConfigError(String::from(
+                    "TRSO_DIRECTION should be either up or down",
+                )))
+            }
+        };
+
+        let output = match var("TRSO_OUTPUT").as_deref() {
+            None | Some("text") => OutputFormat::Text,
+            Some("json") => OutputFormat::Json,
+            Some(_) => {
+                return Err(AppError::ConfigError(String::from(
+                    "TRSO_OUTPUT should be either text or json",
+                )))
+            }
+        };
+
+        let tracking = match var("TRSO_TRACKING").as_deref() {
+            None | Some("table") => Tracking::Table,
+            Some("user_version") => Tracking::UserVersion,
+            Some(_) => {
+                return Err(AppError::ConfigError(String::from(
+                    "TRSO_TRACKING should be either table or user_version",
+                )))
+            }
+        };
+
+        let table_name = var("TRSO_TABLE_NAME").unwrap_or(String::from("migrations"));
+        match validate_table_name(&table_name) {
+            Ok(_) => {}
+            Err(AppError::ConfigError(message)) => {
+                return Err(AppError::ConfigError(format!("invalid TRSO_TABLE_NAME: {}", message)))
+            }
+            Err(e) => return Err(e),
+        }
+
+        // columns of an adopted table are interpolated into SQL like the table name
+        let column = |name: &str, default: &str| {
+            let column = var(name).unwrap_or(String::from(default));
+            match validate_table_name(&column) {
+                Ok(_) => Ok(column),
+                Err(_) => Err(AppError::ConfigError(format!(
+                    "{} should be a column name matching ^[A-Za-z_][A-Za-z0-9_]*$",
+                    name
+                ))),
+            }
+        };
+        let file_name_column = column("TRSO_COLUMN_FILE_NAME", "file_name")?;
+        let applied_at_column = column("TRSO_COLUMN_APPLIED_AT", "applied_at")?;
+        // only written when it's mapped
+        let version_column = if vars.contains_key("TRSO_COLUMN_VERSION") {
+            Some(column("TRSO_COLUMN_VERSION", "")?)
+        } else {
+            None
+        };
+
+        let naming_pattern = if flag(vars, "TRSO_ENFORCE_NAMING")? {
+            Some(var("TRSO_NAMING_PATTERN").unwrap_or(String::from(DEFAULT_NAMING_PATTERN)))
+        } else {
+            None
+        };
+
+        let before_hook = var("TRSO_BEFORE_HOOK");
+        let after_hook = var("TRSO_AFTER_HOOK");
+        for (name, hook) in [
+            ("TRSO_BEFORE_HOOK", &before_hook),
+            ("TRSO_AFTER_HOOK", &after_hook),
+        ] {
+            if hook.as_ref().is_some_and(|path| !Path::new(path).is_file()) {
+                return Err(AppError::ConfigError(format!(
+                    "{} file does not exist",
+                    name
+                )));
+            }
+        }
+
+        Ok(Config {
+            url_or_path,
+            local,
+            token,
+            migrations_path: var("TRSO_MIGRATIONS_PATH").unwrap_or(String::from("migrations")),
+            direction,
+            dry_run: flag(vars, "TRSO_DRY_RUN")?,
+            skip_checksum_verify: flag(vars, "TRSO_SKIP_CHECKSUM_VERIFY")?,
+            match_checksums: flag(vars, "TRSO_MATCH_CHECKSUMS")?,
+            single_transaction: flag(vars, "TRSO_SINGLE_TRANSACTION")?,
+            commit_every: number(vars, "TRSO_COMMIT_EVERY", "a number of migrations", 0)?,
+            batch_execution: flag(vars, "TRSO_BATCH_EXECUTION")?,
+            strict_order: flag(vars, "TRSO_STRICT_ORDER")?,
+            lock_timeout_secs: number(vars, "TRSO_LOCK_TIMEOUT_SECS", "a number of seconds", 0)?,
+            replica_path,
+            sync_timeout_secs: number(vars, "TRSO_SYNC_TIMEOUT_SECS", "a number of seconds", 60)?,
+            sync_best_effort: flag(vars, "TRSO_SYNC_BEST_EFFORT")?,
+            table_name,
+            file_name_column,
+            applied_at_column,
+            version_column,
+            tracking,
+            recursive: flag(vars, "TRSO_RECURSIVE")?,
+            output,
+            connect_retries: number(vars, "TRSO_CONNECT_RETRIES", "a number", 5)?,
+            exec_retries: number(vars, "TRSO_EXEC_RETRIES", "a number", 0)?,
+            statement_timeout_secs: number(
+                vars,
+                "TRSO_STATEMENT_TIMEOUT_SECS",
+                "a number of seconds",
+                0,
+            )?,
+            read_your_writes,
+            namespace,
+            naming_pattern,
+            target: var("TRSO_TARGET"),
+            template: flag(vars, "TRSO_TEMPLATE")?,
+            strict: flag(vars, "TRSO_STRICT")?,
+            before_hook,
+            after_hook,
+            pragmas: pragmas(vars)?,
+            allow_duplicate_versions: flag(vars, "TRSO_ALLOW_DUPLICATE_VERSIONS")?,
+            environment: var("TRSO_ENV"),
+            webhook_url: var("TRSO_WEBHOOK_URL"),
+            watch_poll_ms: number(vars, "TRSO_WATCH_POLL_MS", "a number of milliseconds", 500)?,
+            watch_debounce_ms: number(
+                vars,
+                "TRSO_WATCH_DEBOUNCE_MS",
+                "a number of milliseconds",
+                300,
+            )?,
+            ..Default::default()
+        })
+    }
+}
+
+/// A `true`/`false` variable, `false` when it isn't set.
+fn flag(vars: &HashMap<String, String>, name: &str) -> Result<bool, AppError> {
+    match vars.get(name).map(|value| value.parse::<bool>()) {
+        None => Ok(false),
+        Some(Ok(value)) => Ok(value),
+        Some(Err(_)) => Err(AppError::ConfigError(format!(
+            "{} should be either true or false",
+            name
+        ))),
+    }
+}
+
+/// A numeric variable, `default` when it isn't set.
+fn number<T: std::str::FromStr>(
+    vars: &HashMap<String, String>,
+    name: &str,
+    expected: &str,
+    default: T,
+) -> Result<T, AppError> {
+    match vars.get(name).map(|value| value.parse::<T>()) {
+        None => Ok(default),
+        Some(Ok(value)) => Ok(value),
+        Some(Err(_)) => Err(AppError::ConfigError(format!(
+            "{} should be {}",
+            name, expected
+        ))),
+    }
+}
+
+/// The PRAGMAs of the `TRSO_PRAGMA_*` variables as name and value.
+fn pragmas(vars: &HashMap<String, String>) -> Result<Vec<(String, String)>, AppError> {
+    let mut pragmas = Vec::new();
+    for (name, var, valid) in [
+        ("foreign_keys", "TRSO_PRAGMA_FOREIGN_KEYS", "ON|OFF"),
+        (
+            "journal_mode",
+            "TRSO_PRAGMA_JOURNAL_MODE",
+            "DELETE|TRUNCATE|PERSIST|MEMORY|WAL|OFF",
+        ),
+        ("busy_timeout", "TRSO_PRAGMA_BUSY_TIMEOUT", ""),
+        (
+            "synchronous",
+            "TRSO_PRAGMA_SYNCHRONOUS",
+            "OFF|NORMAL|FULL|EXTRA",
+        ),
+    ] {
+        let value = match vars.get(var) {
+            Some(value) => value.to_uppercase(),
+            None => continue,
+        };
+
+        // busy_timeout is the only one taking milliseconds instead of a keyword
+        let is_valid = if valid.is_empty() {
+            value.parse::<u64>().is_ok()
+        } else {
+            valid.split('|').any(|v| v == value)
+        };
+        if !is_valid {
+            let expected = if valid.is_empty() {
+                String::from("a number of milliseconds")
+            } else {
+                format!("one of {}", valid.replace('|', ", "))
+            };
+            return Err(AppError::ConfigError(format!(
+                "{} should be {}",
+                var, expected
+            )));
+        }
+
+        pragmas.push((name.to_string(), value));
+    }
+
+    Ok(pragmas)
+}
+
+/// Reads a token from a mounted secret, the trailing newline is dropped.
+fn read_token_file(path: &str) -> Result<String, AppError> {
+    match std::fs::read_to_string(path) {
+        Ok(token) => Ok(token.trim_end().to_string()),
+        Err(e) => Err(AppError::IOError(format!(
+            "cannot read the token file {}: {}",
+            path, e
+        ))),
+    }
+}
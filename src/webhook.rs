@@ -0,0 +1,92 @@
+//! Reports the outcome of a run to `Config::webhook_url`, e.g. a chat channel
+//! or deploy dashboard.
+
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::{AppError, Config};
+
+/// The JSON body posted to the webhook.
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    /// Url or path of the migrated database.
+    pub database: String,
+
+    pub success: bool,
+
+    /// Applied migrations, in dry run mode the pending ones.
+    pub applied: usize,
+
+    /// The migration file that failed, if the run stopped at one.
+    pub failed_file: Option<String>,
+
+    pub error: Option<String>,
+
+    pub duration_ms: u128,
+
+    pub target: Option<String>,
+
+    pub dry_run: bool,
+}
+
+impl RunSummary {
+    /// The summary of a run of `config` that ended with `result`, the number
+    /// of applied migrations or the error it stopped with.
+    pub fn new(config: &Config, result: &Result<usize, AppError>, duration: Duration) -> Self {
+        let failed_file = match result {
+            Err(AppError::MigrationFailed { file, .. }) => Some(file.clone()),
+            _ => None,
+        };
+
+        RunSummary {
+            database: config.url_or_path.clone(),
+            success: result.is_ok(),
+            applied: *result.as_ref().unwrap_or(&0),
+            failed_file,
+            error: result.as_ref().err().map(|e| e.to_string()),
+            duration_ms: duration.as_millis(),
+            target: config.target.clone(),
+            dry_run: config.dry_run,
+        }
+    }
+}
+
+/// Posts `summary` to the webhook of `config`, if one is set. A webhook that
+/// can't be reached or answers with an error is only logged, it never fails
+/// the run it reports on.
+pub async fn notify_webhook(config: &Config, summary: &RunSummary) {
+    let url = match &config.webhook_url {
+        Some(url) => url,
+        None => return,
+    };
+
+    match tokio::time::timeout(Duration::from_secs(10), post(url, summary)).await {
+        Ok(Ok(())) => debug!("Posted the run summary to the webhook"),
+        Ok(Err(e)) => warn!("cannot post the run summary to the webhook: {}", e),
+        Err(_) => warn!("cannot post the run summary to the webhook, it timed out"),
+    }
+}
+
+async fn post(url: &str, summary: &RunSummary) -> Result<(), String> {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client: hyper::Client<_, hyper::Body> = hyper::Client::builder().build(connector);
+
+    let body = serde_json::to_string(summary).unwrap();
+    let request = hyper::Request::post(url)
+        .header("content-type", "application/json")
+        .body(hyper::Body::from(body))
+        .map_err(|e| e.to_string())?;
+
+    let response = client.request(request).await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("it answered {}", response.status()));
+    }
+
+    Ok(())
+}
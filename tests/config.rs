@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use trso_migrator::{AppError, Config};
+
+fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+}
+
+/// The message of a config error, panics on anything else.
+fn config_error(vars: &HashMap<String, String>) -> String {
+    match Config::from_map(vars) {
+        Err(AppError::ConfigError(message)) => message,
+        other => panic!("expected a config error, got {:?}", other),
+    }
+}
+
+#[test]
+fn dsn_mode_takes_the_url_and_token_of_the_dsn() {
+    let config = Config::from_map(&vars(&[
+        ("TRSO_DSN", "libsql://db-org.turso.io?authToken=secret"),
+        ("TRSO_PATH_URL", "ignored.db"),
+        ("TRSO_LOCAL", "true"),
+    ]))
+    .unwrap();
+
+    assert_eq!(config.url_or_path, "libsql://db-org.turso.io");
+    assert_eq!(config.token, "secret");
+    assert!(!config.local);
+}
+
+#[test]
+fn local_mode_needs_no_token() {
+    let config =
+        Config::from_map(&vars(&[("TRSO_LOCAL", "true"), ("TRSO_PATH_URL", "local.db")])).unwrap();
+
+    assert_eq!(config.url_or_path, "local.db");
+    assert!(config.token.is_empty());
+    assert!(config.local);
+    assert_eq!(config.table_name, "migrations");
+}
+
+#[test]
+fn path_url_is_required_without_a_dsn() {
+    let message = config_error(&vars(&[("TRSO_LOCAL", "true")]));
+
+    assert!(message.contains("TRSO_PATH_URL"));
+}
+
+#[test]
+fn remote_url_needs_a_token() {
+    let message = config_error(&vars(&[("TRSO_PATH_URL", "libsql://db-org.turso.io")]));
+
+    assert!(message.contains("TRSO_TOKEN"));
+}
+
+#[test]
+fn invalid_values_are_prefixed_once() {
+    let message = config_error(&vars(&[("TRSO_DSN", "postgres://localhost/db")]));
+    assert!(message.starts_with("invalid TRSO_DSN: "));
+    assert!(!message.contains("config error"));
+
+    let message = config_error(&vars(&[
+        ("TRSO_LOCAL", "true"),
+        ("TRSO_PATH_URL", "local.db"),
+        ("TRSO_TABLE_NAME", "drop table"),
+    ]));
+    assert!(message.starts_with("invalid TRSO_TABLE_NAME: "));
+    assert!(!message.contains("config error"));
+}
+
+#[test]
+fn version_column_is_only_set_when_mapped() {
+    let local = [("TRSO_LOCAL", "true"), ("TRSO_PATH_URL", "local.db")];
+    assert_eq!(Config::from_map(&vars(&local)).unwrap().version_column, None);
+
+    let mapped = [local[0], local[1], ("TRSO_COLUMN_VERSION", "version")];
+    let config = Config::from_map(&vars(&mapped)).unwrap();
+    assert_eq!(config.version_column.as_deref(), Some("version"));
+
+    let invalid = [local[0], local[1], ("TRSO_COLUMN_VERSION", "version; --")];
+    assert!(config_error(&vars(&invalid)).contains("TRSO_COLUMN_VERSION"));
+}
+
+#[test]
+fn dsn_errors_leave_the_token_out() {
+    for dsn in [
+        "postgres://localhost/db?authToken=secret",
+        "libsql://db-org.turso.io?authToken=secret&tls=maybe",
+        "libsql://db-org.turso.io?readYourWrites=no&authToken=secret",
+        "file:?authToken=secret",
+    ] {
+        let error = Config::from_map(&vars(&[("TRSO_DSN", dsn)])).unwrap_err();
+
+        assert!(!error.to_string().contains("secret"), "{} leaks the token", dsn);
+        assert!(!format!("{:?}", error).contains("secret"), "{} leaks the token", dsn);
+    }
+}
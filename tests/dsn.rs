@@ -0,0 +1,83 @@
+use trso_migrator::{parse_dsn, AppError};
+
+#[test]
+fn libsql_scheme_is_remote() {
+    let dsn = parse_dsn("libsql://db-org.turso.io?authToken=secret").unwrap();
+
+    assert_eq!(dsn.url_or_path, "libsql://db-org.turso.io");
+    assert_eq!(dsn.token.as_deref(), Some("secret"));
+    assert!(!dsn.local);
+}
+
+#[test]
+fn http_and_https_schemes_are_remote() {
+    for url in ["http://127.0.0.1:8080", "https://db-org.turso.io"] {
+        let dsn = parse_dsn(&format!("{}?authToken=secret", url)).unwrap();
+
+        assert_eq!(dsn.url_or_path, url);
+        assert!(!dsn.local);
+    }
+}
+
+#[test]
+fn file_scheme_is_local() {
+    let dsn = parse_dsn("file:local.db").unwrap();
+    assert_eq!(dsn.url_or_path, "local.db");
+    assert!(dsn.local);
+
+    let dsn = parse_dsn("file:///var/lib/app.db").unwrap();
+    assert_eq!(dsn.url_or_path, "/var/lib/app.db");
+    assert!(dsn.local);
+}
+
+#[test]
+fn file_scheme_needs_a_path() {
+    assert!(matches!(parse_dsn("file:"), Err(AppError::ConfigError(_))));
+}
+
+#[test]
+fn unknown_scheme_is_rejected() {
+    match parse_dsn("postgres://localhost/db?authToken=secret") {
+        Err(AppError::ConfigError(message)) => assert!(message.contains("postgres://")),
+        other => panic!("expected a config error, got {:?}", other),
+    }
+}
+
+#[test]
+fn tls_false_talks_plain_http() {
+    let dsn = parse_dsn("libsql://127.0.0.1:8080?authToken=secret&tls=false").unwrap();
+
+    assert_eq!(dsn.url_or_path, "http://127.0.0.1:8080");
+}
+
+#[test]
+fn other_parameters_are_kept() {
+    let dsn = parse_dsn("libsql://db.turso.io?authToken=secret&namespace=tenant").unwrap();
+
+    assert_eq!(dsn.param("namespace"), Some("tenant"));
+    assert_eq!(dsn.param("authToken"), None);
+}
+
+#[test]
+fn local_dsn_needs_no_token() {
+    for dsn in ["file:local.db", "local.db", "/var/lib/app.db"] {
+        let parsed = parse_dsn(dsn).unwrap();
+
+        assert!(parsed.local, "{} should be local", dsn);
+        assert_eq!(parsed.token, None);
+    }
+}
+
+#[test]
+fn remote_dsn_needs_a_token() {
+    for dsn in [
+        "libsql://db.turso.io",
+        "https://db.turso.io?authToken=",
+        "http://127.0.0.1:8080?tls=false",
+    ] {
+        match parse_dsn(dsn) {
+            Err(AppError::ConfigError(message)) => assert!(message.contains("authToken")),
+            other => panic!("expected {} to need a token, got {:?}", dsn, other),
+        }
+    }
+}
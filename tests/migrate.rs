@@ -0,0 +1,603 @@
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use libsql::{Builder, Connection, Database};
+use trso_migrator::{
+    check_down_files, migrate_database, rollback_database, AppError, ArchiveSource, Config,
+    DownPairing, FsSource, MigrationSource, Tracking,
+};
+
+static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+
+/// A folder in the temp dir that is removed again when the test is done.
+struct Fixture(PathBuf);
+
+impl Deref for Fixture {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A fresh folder holding `files`, names may contain subfolders.
+fn fixture(files: &[(&str, &str)]) -> Fixture {
+    let dir = std::env::temp_dir().join(format!(
+        "trso-migrator-{}-{}",
+        std::process::id(),
+        FIXTURES.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir_all(&dir).unwrap();
+
+    for (name, content) in files {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    Fixture(dir)
+}
+
+/// The database has to outlive its connection, so both are returned.
+async fn memory() -> (Database, Connection) {
+    let db = Builder::new_local(":memory:").build().await.unwrap();
+    let conn = db.connect().unwrap();
+
+    (db, conn)
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        local: true,
+        migrations_path: dir.to_string_lossy().to_string(),
+        ..Default::default()
+    }
+}
+
+/// The recorded file names of `table` in the order they were applied.
+async fn recorded(conn: &Connection, table: &str) -> Vec<String> {
+    let sql = format!("SELECT file_name FROM {} ORDER BY rowid", table);
+    let mut rows = conn.query(&sql, ()).await.unwrap();
+
+    let mut names = Vec::new();
+    while let Some(row) = rows.next().await.unwrap() {
+        names.push(row.get::<String>(0).unwrap());
+    }
+
+    names
+}
+
+async fn table_exists(conn: &Connection, name: &str) -> bool {
+    let mut rows = conn
+        .query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1", [name])
+        .await
+        .unwrap();
+
+    rows.next().await.unwrap().is_some()
+}
+
+async fn columns(conn: &Connection, table: &str) -> Vec<String> {
+    let mut rows = conn.query(&format!("PRAGMA table_info({})", table), ()).await.unwrap();
+
+    let mut columns = Vec::new();
+    while let Some(row) = rows.next().await.unwrap() {
+        columns.push(row.get::<String>(1).unwrap());
+    }
+
+    columns
+}
+
+#[tokio::test]
+async fn records_applied_files_in_order() {
+    let (_db, conn) = memory().await;
+    let dir = fixture(&[
+        ("001_users.sql", "CREATE TABLE users (id INTEGER PRIMARY KEY);"),
+        ("002_posts.sql", "CREATE TABLE posts (id INTEGER PRIMARY KEY);"),
+    ]);
+    let config = config(&dir);
+
+    let report = migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+
+    assert_eq!(report.applied, ["001_users.sql", "002_posts.sql"]);
+    assert!(table_exists(&conn, "users").await);
+    assert!(table_exists(&conn, "posts").await);
+
+    let mut rows = conn
+        .query("SELECT file_name, applied_at, checksum FROM migrations ORDER BY rowid", ())
+        .await
+        .unwrap();
+    let mut names = Vec::new();
+    while let Some(row) = rows.next().await.unwrap() {
+        names.push(row.get::<String>(0).unwrap());
+        assert!(!row.get::<String>(1).unwrap().is_empty());
+        assert_eq!(row.get::<String>(2).unwrap().len(), 64);
+    }
+    assert_eq!(names, ["001_users.sql", "002_posts.sql"]);
+}
+
+#[tokio::test]
+async fn second_run_applies_nothing() {
+    let (_db, conn) = memory().await;
+    let dir = fixture(&[
+        ("001_users.sql", "CREATE TABLE users (id INTEGER PRIMARY KEY);"),
+        ("002_posts.sql", "CREATE TABLE posts (id INTEGER PRIMARY KEY);"),
+    ]);
+    let config = config(&dir);
+    let source = FsSource::from_config(&config);
+
+    migrate_database(&conn, &config, &source).await.unwrap();
+    let report = migrate_database(&conn, &config, &source).await.unwrap();
+
+    assert!(report.applied.is_empty());
+    assert_eq!(report.skipped, ["001_users.sql", "002_posts.sql"]);
+    assert_eq!(recorded(&conn, "migrations").await, ["001_users.sql", "002_posts.sql"]);
+}
+
+#[tokio::test]
+async fn failing_file_is_rolled_back() {
+    let (_db, conn) = memory().await;
+    let dir = fixture(&[
+        ("001_users.sql", "CREATE TABLE users (id INTEGER PRIMARY KEY);"),
+        (
+            "002_posts.sql",
+            "CREATE TABLE posts (id INTEGER PRIMARY KEY);\nINSERT INTO missing VALUES (1);",
+        ),
+        ("003_tags.sql", "CREATE TABLE tags (id INTEGER PRIMARY KEY);"),
+    ]);
+    let config = config(&dir);
+
+    let result = migrate_database(&conn, &config, &FsSource::from_config(&config)).await;
+
+    match result {
+        Err(AppError::MigrationFailed { file, .. }) => assert_eq!(file, "002_posts.sql"),
+        other => panic!("expected 002_posts.sql to fail, got {:?}", other),
+    }
+    // the statement before the failing one is undone with it, later files never run
+    assert!(table_exists(&conn, "users").await);
+    assert!(!table_exists(&conn, "posts").await);
+    assert!(!table_exists(&conn, "tags").await);
+    assert_eq!(recorded(&conn, "migrations").await, ["001_users.sql"]);
+}
+
+#[tokio::test]
+async fn table_names_track_independent_state() {
+    let (_db, conn) = memory().await;
+    let app = fixture(&[("001_init.sql", "CREATE TABLE users (id INTEGER);")]);
+    let tenant = fixture(&[("001_init.sql", "CREATE TABLE tenants (id INTEGER);")]);
+
+    let app_config = Config {
+        table_name: String::from("app_migrations"),
+        ..config(&app)
+    };
+    let tenant_config = Config {
+        table_name: String::from("tenant_migrations"),
+        ..config(&tenant)
+    };
+
+    let report = migrate_database(&conn, &app_config, &FsSource::from_config(&app_config))
+        .await
+        .unwrap();
+    assert_eq!(report.applied, ["001_init.sql"]);
+
+    // the file recorded in app_migrations is still pending for the other table
+    let report = migrate_database(&conn, &tenant_config, &FsSource::from_config(&tenant_config))
+        .await
+        .unwrap();
+    assert_eq!(report.applied, ["001_init.sql"]);
+
+    assert_eq!(recorded(&conn, "app_migrations").await, ["001_init.sql"]);
+    assert_eq!(recorded(&conn, "tenant_migrations").await, ["001_init.sql"]);
+}
+
+#[tokio::test]
+async fn numbers_sort_numerically() {
+    let (_db, conn) = memory().await;
+    let dir = fixture(&[
+        ("10_tags.sql", "CREATE TABLE tags (id INTEGER);"),
+        ("2_posts.sql", "CREATE TABLE posts (id INTEGER);"),
+        ("1_users.sql", "CREATE TABLE users (id INTEGER);"),
+    ]);
+    let config = config(&dir);
+
+    let report = migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+
+    assert_eq!(report.applied, ["1_users.sql", "2_posts.sql", "10_tags.sql"]);
+}
+
+#[tokio::test]
+async fn files_without_statements_are_recorded_as_applied() {
+    let (_db, conn) = memory().await;
+    let dir = fixture(&[
+        ("001_empty.sql", ""),
+        ("002_blank.sql", "  \n\t\n"),
+        ("003_comments.sql", "-- filled in later\n/* nothing; yet */\n"),
+        ("004_users.sql", "CREATE TABLE users (id INTEGER)"),
+    ]);
+    let config = config(&dir);
+
+    let report = migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        report.applied,
+        ["001_empty.sql", "002_blank.sql", "003_comments.sql", "004_users.sql"]
+    );
+    assert_eq!(recorded(&conn, "migrations").await.len(), 4);
+    // the last statement ran without a trailing semicolon
+    assert!(table_exists(&conn, "users").await);
+}
+
+#[tokio::test]
+async fn same_names_in_subfolders_are_recorded_by_their_path() {
+    let (_db, conn) = memory().await;
+    let dir = fixture(&[
+        ("app/001_init.sql", "CREATE TABLE users (id INTEGER);"),
+        ("billing/001_init.sql", "CREATE TABLE invoices (id INTEGER);"),
+    ]);
+    // both files share version 001
+    let config = Config {
+        recursive: true,
+        allow_duplicate_versions: true,
+        ..config(&dir)
+    };
+    let source = FsSource::from_config(&config);
+
+    let report = migrate_database(&conn, &config, &source).await.unwrap();
+
+    assert_eq!(report.applied, ["app/001_init.sql", "billing/001_init.sql"]);
+    assert!(table_exists(&conn, "invoices").await);
+    assert_eq!(
+        recorded(&conn, "migrations").await,
+        ["app/001_init.sql", "billing/001_init.sql"]
+    );
+
+    let report = migrate_database(&conn, &config, &source).await.unwrap();
+    assert!(report.applied.is_empty());
+}
+
+#[tokio::test]
+async fn plain_names_follow_files_moved_into_a_subfolder() {
+    let (_db, conn) = memory().await;
+    let flat = fixture(&[("001_users.sql", "CREATE TABLE users (id INTEGER);")]);
+    migrate_database(&conn, &config(&flat), &FsSource::from_config(&config(&flat)))
+        .await
+        .unwrap();
+
+    let nested = fixture(&[("2024/001_users.sql", "CREATE TABLE users (id INTEGER);")]);
+    let config = Config {
+        recursive: true,
+        ..config(&nested)
+    };
+    let report = migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+
+    assert!(report.applied.is_empty());
+    assert_eq!(recorded(&conn, "migrations").await, ["2024/001_users.sql"]);
+}
+
+#[tokio::test]
+async fn output_sql_leaves_the_database_untouched() {
+    let (_db, conn) = memory().await;
+    let dir = fixture(&[("001_users.sql", "CREATE TABLE users (id INTEGER);")]);
+    // outside of the migrations folder, it would be a pending file there
+    let out = fixture(&[]);
+    let script = out.join("pending.sql");
+    let config = Config {
+        dry_run: true,
+        output_sql: Some(script.clone()),
+        ..config(&dir)
+    };
+
+    let report = migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+
+    assert_eq!(report.applied, ["001_users.sql"]);
+    assert!(!table_exists(&conn, "migrations").await);
+    assert!(!table_exists(&conn, "migration_meta").await);
+
+    // the script creates the table it records the migrations in
+    conn.execute_batch(&fs::read_to_string(script).unwrap()).await.unwrap();
+    assert!(table_exists(&conn, "users").await);
+    assert_eq!(recorded(&conn, "migrations").await, ["001_users.sql"]);
+}
+
+#[tokio::test]
+async fn output_sql_upgrades_an_old_table() {
+    let (_db, conn) = memory().await;
+    conn.execute_batch(
+        "CREATE TABLE migrations (id INTEGER PRIMARY KEY AUTOINCREMENT, file_name TEXT);
+         INSERT INTO migrations (file_name) VALUES ('001_users.sql');",
+    )
+    .await
+    .unwrap();
+    let dir = fixture(&[
+        ("001_users.sql", "CREATE TABLE users (id INTEGER);"),
+        ("002_posts.sql", "-- trso:tags blog\nCREATE TABLE posts (id INTEGER);"),
+    ]);
+    // outside of the migrations folder, it would be a pending file there
+    let out = fixture(&[]);
+    let script = out.join("pending.sql");
+    let config = Config {
+        dry_run: true,
+        output_sql: Some(script.clone()),
+        ..config(&dir)
+    };
+
+    let report = migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+
+    assert_eq!(report.applied, ["002_posts.sql"]);
+    assert_eq!(columns(&conn, "migrations").await, ["id", "file_name"]);
+    assert!(!table_exists(&conn, "migration_meta").await);
+
+    // the columns the inserts name are added by the script first
+    conn.execute_batch(&fs::read_to_string(script).unwrap()).await.unwrap();
+    assert_eq!(recorded(&conn, "migrations").await, ["001_users.sql", "002_posts.sql"]);
+    assert_eq!(
+        columns(&conn, "migrations").await,
+        ["id", "file_name", "applied_at", "checksum", "duration_ms", "tags"]
+    );
+
+    // a normal run finds the table up to date
+    let config = Config { dry_run: false, output_sql: None, ..config };
+    let report = migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+    assert!(report.applied.is_empty());
+}
+
+#[tokio::test]
+async fn adopted_tables_only_get_the_mapped_columns() {
+    let (_db, conn) = memory().await;
+    conn.execute_batch(
+        "CREATE TABLE schema_migrations (version INTEGER NOT NULL, name TEXT NOT NULL, run_on TEXT);
+         INSERT INTO schema_migrations VALUES (1, '001_users.sql', '2024-01-01 00:00:00');",
+    )
+    .await
+    .unwrap();
+    let dir = fixture(&[
+        ("001_users.sql", "CREATE TABLE users (id INTEGER);"),
+        ("002_posts.sql", "CREATE TABLE posts (id INTEGER);"),
+    ]);
+    let config = Config {
+        table_name: String::from("schema_migrations"),
+        file_name_column: String::from("name"),
+        applied_at_column: String::from("run_on"),
+        version_column: Some(String::from("version")),
+        ..config(&dir)
+    };
+
+    let report = migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+
+    assert_eq!(report.applied, ["002_posts.sql"]);
+    assert!(!table_exists(&conn, "migration_meta").await);
+
+    assert_eq!(columns(&conn, "schema_migrations").await, ["version", "name", "run_on"]);
+
+    let mut rows = conn
+        .query("SELECT version, name FROM schema_migrations ORDER BY rowid", ())
+        .await
+        .unwrap();
+    let mut recorded = Vec::new();
+    while let Some(row) = rows.next().await.unwrap() {
+        recorded.push((row.get::<i64>(0).unwrap(), row.get::<String>(1).unwrap()));
+    }
+    assert_eq!(
+        recorded,
+        [(1, String::from("001_users.sql")), (2, String::from("002_posts.sql"))]
+    );
+}
+
+#[tokio::test]
+async fn a_missing_adopted_table_numbers_its_versions() {
+    let (_db, conn) = memory().await;
+    let dir = fixture(&[("001_users.sql", "CREATE TABLE users (id INTEGER);")]);
+    let config = Config {
+        table_name: String::from("schema_migrations"),
+        file_name_column: String::from("name"),
+        applied_at_column: String::from("run_on"),
+        version_column: Some(String::from("version")),
+        ..config(&dir)
+    };
+
+    migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+
+    let mut rows = conn
+        .query("SELECT typeof(version), version FROM schema_migrations", ())
+        .await
+        .unwrap();
+    let row = rows.next().await.unwrap().unwrap();
+    assert_eq!(row.get::<String>(0).unwrap(), "integer");
+    assert_eq!(row.get::<i64>(1).unwrap(), 1);
+}
+
+#[tokio::test]
+async fn adopted_tables_need_the_mapped_columns() {
+    let (_db, conn) = memory().await;
+    conn.execute("CREATE TABLE schema_migrations (name TEXT, run_on TEXT)", ())
+        .await
+        .unwrap();
+    let dir = fixture(&[("001_users.sql", "CREATE TABLE users (id INTEGER);")]);
+    let config = Config {
+        table_name: String::from("schema_migrations"),
+        file_name_column: String::from("name"),
+        applied_at_column: String::from("run_on"),
+        version_column: Some(String::from("version")),
+        ..config(&dir)
+    };
+
+    match migrate_database(&conn, &config, &FsSource::from_config(&config)).await {
+        Err(AppError::ConfigError(message)) => assert!(message.contains("version")),
+        other => panic!("expected the missing version column, got {:?}", other),
+    }
+    assert!(!table_exists(&conn, "users").await);
+}
+
+#[tokio::test]
+async fn user_version_runs_create_no_tables_of_their_own() {
+    let (_db, conn) = memory().await;
+    let dir = fixture(&[
+        ("001_users.sql", "CREATE TABLE users (id INTEGER);"),
+        ("002_posts.sql", "CREATE TABLE posts (id INTEGER);"),
+    ]);
+    let config = Config {
+        tracking: Tracking::UserVersion,
+        ..config(&dir)
+    };
+
+    let report = migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+
+    assert_eq!(report.applied, ["001_users.sql", "002_posts.sql"]);
+    let mut rows = conn.query("PRAGMA user_version", ()).await.unwrap();
+    assert_eq!(rows.next().await.unwrap().unwrap().get::<i64>(0).unwrap(), 2);
+    for table in ["migrations", "migration_lock", "migration_meta"] {
+        assert!(!table_exists(&conn, table).await, "{} should not exist", table);
+    }
+}
+
+#[tokio::test]
+async fn failed_verification_of_an_optional_file_only_skips_it() {
+    let (_db, conn) = memory().await;
+    let dir = fixture(&[
+        ("001_users.sql", "CREATE TABLE users (id INTEGER);"),
+        ("002_seed.sql", "-- trso:optional\nCREATE TABLE seed (id INTEGER);"),
+        ("002_seed.verify.sql", "SELECT 1 FROM seed"),
+        ("003_posts.sql", "CREATE TABLE posts (id INTEGER);"),
+    ]);
+    let config = Config {
+        single_transaction: true,
+        ..config(&dir)
+    };
+
+    let report = migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+
+    assert_eq!(report.applied, ["001_users.sql", "003_posts.sql"]);
+    assert_eq!(report.failed_optional, ["002_seed.sql"]);
+    assert!(!table_exists(&conn, "seed").await);
+    assert_eq!(recorded(&conn, "migrations").await, ["001_users.sql", "003_posts.sql"]);
+}
+
+/// `001_users` as an up/down pair followed by a forward-only `002_posts.sql`.
+fn paired_fixture() -> Fixture {
+    fixture(&[
+        ("001_users.up.sql", "CREATE TABLE users (id INTEGER);"),
+        ("001_users.down.sql", "DROP TABLE users;"),
+        ("002_posts.sql", "CREATE TABLE posts (id INTEGER);"),
+    ])
+}
+
+#[tokio::test]
+async fn up_files_are_recorded_by_their_base_name() {
+    let (_db, conn) = memory().await;
+    let dir = paired_fixture();
+    let config = config(&dir);
+
+    let report = migrate_database(&conn, &config, &FsSource::from_config(&config))
+        .await
+        .unwrap();
+
+    // the rollback file is never applied going forward
+    assert_eq!(report.applied, ["001_users.sql", "002_posts.sql"]);
+    assert!(table_exists(&conn, "users").await);
+    assert_eq!(recorded(&conn, "migrations").await, ["001_users.sql", "002_posts.sql"]);
+}
+
+#[tokio::test]
+async fn rollback_runs_the_down_file() {
+    let (_db, conn) = memory().await;
+    let dir = fixture(&[
+        ("001_users.up.sql", "CREATE TABLE users (id INTEGER);"),
+        ("001_users.down.sql", "DROP TABLE users;"),
+    ]);
+    let config = config(&dir);
+    let source = FsSource::from_config(&config);
+
+    migrate_database(&conn, &config, &source).await.unwrap();
+    assert_eq!(rollback_database(&conn, &config, &source).await.unwrap(), 1);
+
+    assert!(!table_exists(&conn, "users").await);
+    assert!(recorded(&conn, "migrations").await.is_empty());
+}
+
+#[tokio::test]
+async fn rollback_without_a_down_file_fails() {
+    let (_db, conn) = memory().await;
+    let dir = paired_fixture();
+    let config = config(&dir);
+    let source = FsSource::from_config(&config);
+
+    migrate_database(&conn, &config, &source).await.unwrap();
+
+    match rollback_database(&conn, &config, &source).await {
+        Err(AppError::IOError(message)) => assert!(message.contains("002_posts.down.sql")),
+        other => panic!("expected the missing rollback file, got {:?}", other),
+    }
+    assert_eq!(recorded(&conn, "migrations").await, ["001_users.sql", "002_posts.sql"]);
+}
+
+#[test]
+fn forward_only_files_only_need_a_down_file_with_pairing_all() {
+    let dir = paired_fixture();
+    let config = config(&dir);
+    let source = FsSource::from_config(&config);
+
+    assert_eq!(check_down_files(&config, &source, DownPairing::UpFiles).unwrap(), 1);
+    match check_down_files(&config, &source, DownPairing::All) {
+        Err(AppError::IOError(message)) => assert!(message.ends_with("002_posts.sql")),
+        other => panic!("expected 002_posts.sql to miss its rollback file, got {:?}", other),
+    }
+}
+
+#[test]
+fn archives_only_read_their_folders_when_recursive() {
+    let dir = fixture(&[]);
+    let archive = dir.join("migrations.tar");
+    let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+    for name in ["001_users.sql", "2024/002_posts.sql", ".git/003_hidden.sql"] {
+        let content = b"CREATE TABLE t (id INTEGER);";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, &content[..]).unwrap();
+    }
+    builder.finish().unwrap();
+    drop(builder);
+
+    let names = |recursive| {
+        let source = ArchiveSource::open(&archive, recursive).unwrap();
+        let mut names: Vec<String> =
+            source.list().unwrap().into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(names(false), ["001_users.sql"]);
+    assert_eq!(names(true), ["001_users.sql", "2024/002_posts.sql"]);
+}